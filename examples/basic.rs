@@ -6,21 +6,26 @@ use defmt::{info, unwrap, warn};
 use defmt_rtt as _;
 use embassy_executor::Spawner;
 use embassy_net::{Config, DhcpConfig, Stack, StackResources};
-use embassy_net_rp_self_debug::debug::socket::DebugSocket;
-use embassy_net_rp_self_debug::{OtaDebugger, State};
+use embassy_net_rp_self_debug::debug::socket::{self, DebugSocket};
+use embassy_net_rp_self_debug::{OtaDebugger, Route, RouteTarget, State};
 use embassy_rp::bind_interrupts;
 use embassy_rp::clocks::RoscRng;
 use embassy_rp::gpio::{Level, Output};
 use embassy_rp::peripherals::{DMA_CH1, PIN_23, PIO0};
 use embassy_rp::pio::{InterruptHandler, Pio};
 use embassy_rp::watchdog::Watchdog;
-use embassy_time::{Duration, Ticker};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Ticker, Timer};
 use panic_probe as _;
 use rand::RngCore;
 use static_cell::StaticCell;
 
 const FLASH_SIZE: usize = 2048 * 1024;
 
+/// Signalled once the network is up, for the trial boot health check.
+static NETWORK_UP: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
 bind_interrupts!(struct Irqs0 {
     PIO0_IRQ_0 => InterruptHandler<PIO0>;
 });
@@ -84,6 +89,7 @@ async fn net_init(
     stack.wait_config_up().await;
 
     info!("Network up {}", stack.config_v4().unwrap().address);
+    NETWORK_UP.signal(());
 }
 
 #[embassy_executor::task]
@@ -144,10 +150,27 @@ async fn main(spawner: Spawner) {
     })
     .await;
 
+    // Allow the cyw43 firmware blobs (see `net_init`) to be updated in place, whilst protecting
+    // the crash dumps beyond them.
+    static ROUTES: [Route; 2] = [
+        Route::new(0x1010_8000..0x1015_0000, RouteTarget::Partition),
+        Route::new(0x1015_0000..0x1015_8000, RouteTarget::Reject),
+    ];
+    ota_debugger.set_routes(&ROUTES).await;
+
     spawner.must_spawn(net_init(spi, pin_23, debug_socket));
 
+    // Only keep an update if the network comes up and the debug server is reachable,
+    // otherwise the bootloader reverts to the previous image.
+    let health_check = async {
+        NETWORK_UP.wait().await;
+        while !socket::is_listening() {
+            Timer::after_millis(100).await;
+        }
+        true
+    };
     if ota_debugger
-        .with_firmware_updater_blocking(|flash| flash.mark_booted())
+        .trial_boot(Duration::from_secs(60), health_check)
         .await
         .is_err()
     {
@@ -5,9 +5,12 @@ MEMORY
   BOOTLOADER_STATE                  : ORIGIN = 0x10006000, LENGTH = 4K
   FLASH                             : ORIGIN = 0x10007000, LENGTH = 512K
   DFU                               : ORIGIN = 0x10087000, LENGTH = 516K
+  /* Crash dumps are persisted here, beyond any firmware blobs appended to the image */
+  CRASH_DUMP                        : ORIGIN = 0x10150000, LENGTH = 32K
 
-  /* The first 1k of RAM is reserved for the OTA flash algorithm itself */
+  /* Reserved for the OTA flash algorithm itself, which may be moved     */
   /* (fixed-address trampolines into FLASH and space for its stack)     */
+  OTA_ALGORITHM: ORIGIN = 0x20000000, LENGTH = 1k
   RAM: ORIGIN = 0x20000400, LENGTH = 256K - 1k
   /* The unstriped areas are used for data by the OTA flash algorithm   */
   SCRATCH_A: ORIGIN = 0x20040000, LENGTH = 4K
@@ -19,3 +22,22 @@ __bootloader_state_end = ORIGIN(BOOTLOADER_STATE) + LENGTH(BOOTLOADER_STATE) - O
 
 __bootloader_dfu_start = ORIGIN(DFU) - ORIGIN(BOOT2);
 __bootloader_dfu_end = ORIGIN(DFU) + LENGTH(DFU) - ORIGIN(BOOT2);
+
+__crash_dump_start = ORIGIN(CRASH_DUMP) - ORIGIN(BOOT2);
+__crash_dump_end = ORIGIN(CRASH_DUMP) + LENGTH(CRASH_DUMP) - ORIGIN(BOOT2);
+
+__ota_algorithm_start = ORIGIN(OTA_ALGORITHM);
+__ota_algorithm_end = ORIGIN(OTA_ALGORITHM) + LENGTH(OTA_ALGORITHM);
+__ram_start = ORIGIN(RAM);
+
+/* See src/flash/layout.rs: the region holds the probe-rs header, the trampolines, the stack    */
+/* (512 bytes) and the protected function table, which the trampolines load from at most 1020  */
+/* bytes on.                                                                                    */
+ASSERT(__ota_algorithm_end <= ORIGIN(RAM) || __ota_algorithm_start >= ORIGIN(RAM) + LENGTH(RAM),
+       "OTA_ALGORITHM must not overlap RAM");
+ASSERT(__ota_algorithm_start % 4 == 0 && LENGTH(OTA_ALGORITHM) % 4 == 0,
+       "OTA_ALGORITHM must be word aligned");
+ASSERT(__ota_algorithm_end % 32 == 0,
+       "OTA_ALGORITHM must end on a 32 byte boundary, for the MPU protecting the function table");
+ASSERT(LENGTH(OTA_ALGORITHM) >= 580 && LENGTH(OTA_ALGORITHM) <= 1052,
+       "OTA_ALGORITHM must be between 580 and 1052 bytes");
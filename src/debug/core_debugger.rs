@@ -0,0 +1,514 @@
+//! On-device access to the debug features of the other core.
+//!
+//! [`CoreDebugger`] drives the same SWD engine used by [`crate::debug::socket::DebugSocket`],
+//! issuing the commands a remote host would. Every operation attaches to the debug port,
+//! performs its transfers and then releases the port, all whilst holding the spinlock (see
+//! [`crate::with_spinlock`]), so that it can be interleaved with remote debug sessions.
+use dap_rs::dap::DapVersion;
+use defmt::{trace, Format};
+
+use crate::debug::dap::{Core, Core0, Core1, Dap, DapEngine, DefaultDapLeds};
+use crate::debug::registers::{
+    BpComp, BpCtrl, CoreRegister, Dcrdr, Dcrsr, Demcr, Dfsr, Dhcsr, DwtComparator, DwtCtrl,
+    DwtFunction,
+};
+use crate::debug::transfer::{ap, dp, Transfer, TransferError, TransferResponse};
+use crate::flash::spinlock::with_spinlock;
+
+const PACKET_SIZE: usize = dap_rs::usb::DAP2_PACKET_SIZE as usize;
+
+const CONNECT_COMMAND_ID: u8 = 0x02;
+const CONNECT_SWD: u8 = 0x01;
+const SWJ_SEQUENCE_COMMAND_ID: u8 = 0x12;
+const DAP_OK: u8 = 0x00;
+
+/// At least 50 clock cycles with SWDIO high.
+const LINE_RESET: [u8; 7] = [0xff; 7];
+/// The 16-bit JTAG-to-SWD select sequence, transmitted LSB first.
+const JTAG_TO_SWD: [u8; 2] = [0x9e, 0xe7];
+const IDLE: [u8; 1] = [0x00];
+
+/// The number of times a status bit is polled before giving up.
+const POLL_ATTEMPTS: usize = 100;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Error {
+    /// The DAP rejected a non-transfer command.
+    Command(u8),
+    Transfer(TransferError),
+    /// The core did not reach the requested state in time.
+    Timeout,
+    /// The operation requires the core to be halted.
+    NotHalted,
+    /// All of the hardware comparators are in use.
+    NoComparator,
+    /// The address can not be matched by the comparator.
+    InvalidAddress,
+    /// The index is beyond the number of tracepoints.
+    InvalidIndex,
+}
+
+impl From<TransferError> for Error {
+    fn from(e: TransferError) -> Self {
+        Self::Transfer(e)
+    }
+}
+
+/// Why the core entered debug state, decoded from DFSR.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum HaltReason {
+    /// A halt request, including the completion of a single step.
+    Request,
+    Breakpoint,
+    Watchpoint,
+    VectorCatch,
+    External,
+    /// The core is halted but DFSR has no bits set (e.g. already cleared).
+    Unknown,
+}
+
+impl From<Dfsr> for HaltReason {
+    fn from(dfsr: Dfsr) -> Self {
+        if dfsr.bkpt() {
+            Self::Breakpoint
+        } else if dfsr.dwttrap() {
+            Self::Watchpoint
+        } else if dfsr.vcatch() {
+            Self::VectorCatch
+        } else if dfsr.external() {
+            Self::External
+        } else if dfsr.halted() {
+            Self::Request
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+/// The accesses which trigger a watchpoint.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl From<WatchKind> for DwtFunction {
+    fn from(kind: WatchKind) -> Self {
+        match kind {
+            WatchKind::Read => DwtFunction::Read,
+            WatchKind::Write => DwtFunction::Write,
+            WatchKind::ReadWrite => DwtFunction::ReadWrite,
+        }
+    }
+}
+
+pub struct CoreDebugger<CORE: Core> {
+    dap: DapEngine<CORE, DefaultDapLeds>,
+}
+
+impl CoreDebugger<Core0> {
+    pub fn core0() -> Self {
+        Self {
+            dap: Dap::core0(DefaultDapLeds::default()),
+        }
+    }
+}
+
+impl CoreDebugger<Core1> {
+    pub fn core1() -> Self {
+        Self {
+            dap: Dap::core1(DefaultDapLeds::default()),
+        }
+    }
+}
+
+impl<CORE: Core> CoreDebugger<CORE> {
+    /// Attach to the debug port and invoke `func` whilst holding the spinlock.
+    pub(crate) async fn with_attached<R>(
+        &mut self,
+        func: impl FnOnce(&mut Self) -> Result<R, Error>,
+    ) -> Result<R, Error> {
+        with_spinlock(
+            |this| async move {
+                let result = this.attach().and_then(|_| func(this));
+                this.dap.suspend();
+                result
+            },
+            self,
+        )
+        .await
+    }
+
+    fn command(&mut self, request: &[u8]) -> Result<(), Error> {
+        let mut response_buffer = [0; PACKET_SIZE];
+        let n = self
+            .dap
+            .process_command(request, &mut response_buffer, DapVersion::V2);
+        match response_buffer[..n] {
+            [id, status, ..] if id == request[0] && status == DAP_OK => Ok(()),
+            // DAP_Connect responds with the selected port rather than a status.
+            [CONNECT_COMMAND_ID, CONNECT_SWD] if request[0] == CONNECT_COMMAND_ID => Ok(()),
+            _ => Err(Error::Command(request[0])),
+        }
+    }
+
+    fn swj_sequence(&mut self, data: &[u8], bits: u8) -> Result<(), Error> {
+        let mut request = [0; 2 + LINE_RESET.len()];
+        request[0] = SWJ_SEQUENCE_COMMAND_ID;
+        request[1] = bits;
+        request[2..2 + data.len()].copy_from_slice(data);
+        self.command(&request[..2 + data.len()])
+    }
+
+    /// Issue a `DAP_Transfer` expecting `N` words in response.
+    fn transfer<const N: usize>(
+        &mut self,
+        build: impl FnOnce(&mut Transfer<'_>),
+    ) -> Result<[u32; N], Error> {
+        let mut request_buffer = [0; PACKET_SIZE];
+        let mut transfer = Transfer::new(&mut request_buffer);
+        build(&mut transfer);
+        debug_assert_eq!(transfer.reads(), N);
+        let expected = transfer.count();
+        let request = transfer.finish();
+
+        let mut response_buffer = [0; PACKET_SIZE];
+        let n = self
+            .dap
+            .process_command(request, &mut response_buffer, DapVersion::V2);
+        let response = TransferResponse::parse(&response_buffer[..n], expected)?;
+
+        let mut values = [0; N];
+        let mut count = 0;
+        for (value, read) in values.iter_mut().zip(response.values()) {
+            *value = read;
+            count += 1;
+        }
+        if count != N {
+            return Err(TransferError::Malformed.into());
+        }
+        Ok(values)
+    }
+
+    /// Switch the port to SWD, power up the debug domain and select the MEM-AP.
+    fn attach(&mut self) -> Result<(), Error> {
+        self.command(&[CONNECT_COMMAND_ID, CONNECT_SWD])?;
+        self.swj_sequence(&LINE_RESET, 51)?;
+        self.swj_sequence(&JTAG_TO_SWD, 16)?;
+        self.swj_sequence(&LINE_RESET, 51)?;
+        self.swj_sequence(&IDLE, 8)?;
+
+        let [dpidr] = self.transfer(|t| {
+            t.read(dp::DPIDR)
+                .write(dp::ABORT, dp::ABORT_CLEAR_ALL)
+                .write(
+                    dp::CTRL_STAT,
+                    dp::CTRL_STAT_CDBGPWRUPREQ | dp::CTRL_STAT_CSYSPWRUPREQ,
+                );
+        })?;
+        trace!("DPIDR: {:#x}", dpidr);
+
+        let acks = dp::CTRL_STAT_CDBGPWRUPACK | dp::CTRL_STAT_CSYSPWRUPACK;
+        self.poll(|this| {
+            let [ctrl_stat] = this.transfer(|t| {
+                t.read(dp::CTRL_STAT);
+            })?;
+            Ok(ctrl_stat & acks == acks)
+        })?;
+
+        self.transfer::<0>(|t| {
+            t.write(dp::SELECT, dp::select(0, 0))
+                .write(ap::CSW, ap::CSW_WORD);
+        })?;
+        Ok(())
+    }
+
+    pub(crate) fn poll(
+        &mut self,
+        mut done: impl FnMut(&mut Self) -> Result<bool, Error>,
+    ) -> Result<(), Error> {
+        for _ in 0..POLL_ATTEMPTS {
+            if done(self)? {
+                return Ok(());
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    pub(crate) fn read_word(&mut self, address: u32) -> Result<u32, Error> {
+        let [value] = self.transfer(|t| {
+            t.read_memory(address);
+        })?;
+        Ok(value)
+    }
+
+    pub(crate) fn write_word(&mut self, address: u32, value: u32) -> Result<(), Error> {
+        self.transfer::<0>(|t| {
+            t.write_memory(address, value);
+        })?;
+        Ok(())
+    }
+
+    /// Read `data.len()` bytes starting at the (possibly unaligned) `address`.
+    pub(crate) fn read_bytes_attached(
+        &mut self,
+        address: u32,
+        data: &mut [u8],
+    ) -> Result<(), Error> {
+        let mut offset = 0;
+        while offset < data.len() {
+            let current = address + offset as u32;
+            let word = self.read_word(current & !0b11)?.to_le_bytes();
+            let start = (current & 0b11) as usize;
+            let n = core::cmp::min(4 - start, data.len() - offset);
+            data[offset..offset + n].copy_from_slice(&word[start..start + n]);
+            offset += n;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn dhcsr(&mut self) -> Result<Dhcsr, Error> {
+        self.read_word(Dhcsr::ADDRESS).map(Dhcsr)
+    }
+
+    pub(crate) fn write_dhcsr(
+        &mut self,
+        configure: impl FnOnce(&mut Dhcsr),
+    ) -> Result<(), Error> {
+        let mut dhcsr = Dhcsr::default();
+        dhcsr.set_c_debugen(true);
+        configure(&mut dhcsr);
+        self.write_word(Dhcsr::ADDRESS, dhcsr.write_value())
+    }
+
+    pub(crate) fn ensure_halted(&mut self) -> Result<(), Error> {
+        if self.dhcsr()?.s_halt() {
+            Ok(())
+        } else {
+            Err(Error::NotHalted)
+        }
+    }
+
+    pub(crate) fn read_core_register_attached(
+        &mut self,
+        register: CoreRegister,
+    ) -> Result<u32, Error> {
+        self.ensure_halted()?;
+        self.write_word(Dcrsr::ADDRESS, Dcrsr::read(register).0)?;
+        self.poll(|this| Ok(this.dhcsr()?.s_regrdy()))?;
+        self.read_word(Dcrdr::ADDRESS)
+    }
+
+    pub(crate) fn write_core_register_attached(
+        &mut self,
+        register: CoreRegister,
+        value: u32,
+    ) -> Result<(), Error> {
+        self.ensure_halted()?;
+        self.write_word(Dcrdr::ADDRESS, value)?;
+        self.write_word(Dcrsr::ADDRESS, Dcrsr::write(register).0)?;
+        self.poll(|this| Ok(this.dhcsr()?.s_regrdy()))
+    }
+
+    /// Request the core halts and wait for it to enter debug state.
+    pub async fn halt(&mut self) -> Result<(), Error> {
+        self.with_attached(|this| {
+            this.write_dhcsr(|dhcsr| dhcsr.set_c_halt(true))?;
+            this.poll(|this| Ok(this.dhcsr()?.s_halt()))
+        })
+        .await
+    }
+
+    /// Resume a halted core. Debug remains enabled so breakpoints and watchpoints stay active.
+    pub async fn resume(&mut self) -> Result<(), Error> {
+        self.with_attached(|this| {
+            this.ensure_halted()?;
+            this.write_dhcsr(|_| {})
+        })
+        .await
+    }
+
+    /// Execute a single instruction with interrupts masked and wait for the core to halt.
+    pub async fn step(&mut self) -> Result<(), Error> {
+        self.with_attached(|this| {
+            this.ensure_halted()?;
+            this.write_dhcsr(|dhcsr| {
+                dhcsr.set_c_step(true);
+                dhcsr.set_c_maskints(true);
+            })?;
+            this.poll(|this| Ok(this.dhcsr()?.s_halt()))?;
+            this.write_dhcsr(|dhcsr| dhcsr.set_c_halt(true))
+        })
+        .await
+    }
+
+    /// Enable halting debug without halting the core. Breakpoints and watchpoints only halt
+    /// the core once debug is enabled, otherwise they escalate to a HardFault.
+    pub async fn enable_debug(&mut self) -> Result<(), Error> {
+        self.with_attached(|this| {
+            let halted = this.dhcsr()?.s_halt();
+            this.write_dhcsr(|dhcsr| dhcsr.set_c_halt(halted))
+        })
+        .await
+    }
+
+    /// Release the core from debug, disabling any breakpoints and watchpoints.
+    pub async fn detach(&mut self) -> Result<(), Error> {
+        self.with_attached(|this| this.write_word(Dhcsr::ADDRESS, Dhcsr::default().write_value()))
+            .await
+    }
+
+    pub async fn is_halted(&mut self) -> Result<bool, Error> {
+        self.with_attached(|this| Ok(this.dhcsr()?.s_halt())).await
+    }
+
+    /// The reason the core is halted, or `None` if it is running. Reading the reason clears
+    /// it, such that a subsequent halt reports only its own cause.
+    pub async fn halt_reason(&mut self) -> Result<Option<HaltReason>, Error> {
+        self.with_attached(|this| {
+            if !this.dhcsr()?.s_halt() {
+                return Ok(None);
+            }
+            let dfsr = Dfsr(this.read_word(Dfsr::ADDRESS)?);
+            this.write_word(Dfsr::ADDRESS, dfsr.clear_value())?;
+            Ok(Some(dfsr.into()))
+        })
+        .await
+    }
+
+    /// Read a core register, the core must be halted.
+    pub async fn read_core_register(&mut self, register: CoreRegister) -> Result<u32, Error> {
+        self.with_attached(|this| this.read_core_register_attached(register))
+            .await
+    }
+
+    /// Write a core register, the core must be halted.
+    pub async fn write_core_register(
+        &mut self,
+        register: CoreRegister,
+        value: u32,
+    ) -> Result<(), Error> {
+        self.with_attached(|this| this.write_core_register_attached(register, value))
+            .await
+    }
+
+    /// Read a word of memory through the debug port, this does not require the core to be halted.
+    pub async fn read_memory(&mut self, address: u32) -> Result<u32, Error> {
+        self.with_attached(|this| this.read_word(address)).await
+    }
+
+    /// Write a word of memory through the debug port, this does not require the core to be halted.
+    pub async fn write_memory(&mut self, address: u32, value: u32) -> Result<(), Error> {
+        self.with_attached(|this| this.write_word(address, value))
+            .await
+    }
+
+    /// Read bytes from memory through the debug port, this does not require the core to be halted.
+    pub async fn read_bytes(&mut self, address: u32, data: &mut [u8]) -> Result<(), Error> {
+        self.with_attached(|this| this.read_bytes_attached(address, data))
+            .await
+    }
+
+    /// Set a hardware breakpoint on the instruction at `address`, returning the comparator used.
+    pub async fn set_breakpoint(&mut self, address: u32) -> Result<usize, Error> {
+        if address >= 0x2000_0000 || address & 1 != 0 {
+            return Err(Error::InvalidAddress);
+        }
+        self.with_attached(|this| {
+            let ctrl = BpCtrl(this.read_word(BpCtrl::ADDRESS)?);
+            let mut free = None;
+            for index in 0..ctrl.num_code() {
+                let comp = BpComp(this.read_word(BpComp::address(index))?);
+                if comp.enable() && comp.comp() == address {
+                    return Ok(index);
+                }
+                if !comp.enable() && free.is_none() {
+                    free = Some(index);
+                }
+            }
+            let index = free.ok_or(Error::NoComparator)?;
+            this.write_word(BpComp::address(index), BpComp::breakpoint(address).0)?;
+
+            let mut ctrl = BpCtrl::default();
+            ctrl.set_enable(true);
+            ctrl.set_key(true);
+            this.write_word(BpCtrl::ADDRESS, ctrl.0)?;
+            Ok(index)
+        })
+        .await
+    }
+
+    /// Clear the hardware breakpoint on the instruction at `address`, if any.
+    pub async fn clear_breakpoint(&mut self, address: u32) -> Result<(), Error> {
+        self.with_attached(|this| {
+            let ctrl = BpCtrl(this.read_word(BpCtrl::ADDRESS)?);
+            for index in 0..ctrl.num_code() {
+                let comp = BpComp(this.read_word(BpComp::address(index))?);
+                if comp.enable() && comp.comp() == address {
+                    this.write_word(BpComp::address(index), 0)?;
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Set a watchpoint on the naturally aligned region of `size` bytes (a power of two)
+    /// at `address`, returning the comparator used.
+    pub async fn set_watchpoint(
+        &mut self,
+        address: u32,
+        size: u32,
+        kind: WatchKind,
+    ) -> Result<usize, Error> {
+        if !size.is_power_of_two() || address & (size - 1) != 0 {
+            return Err(Error::InvalidAddress);
+        }
+        self.with_attached(|this| {
+            let mut demcr = Demcr(this.read_word(Demcr::ADDRESS)?);
+            if !demcr.dwtena() {
+                demcr.set_dwtena(true);
+                this.write_word(Demcr::ADDRESS, demcr.0)?;
+            }
+
+            let ctrl = DwtCtrl(this.read_word(DwtCtrl::ADDRESS)?);
+            let mut free = None;
+            for index in 0..ctrl.num_comp() {
+                let function = this.read_word(DwtComparator::function_address(index))? & 0xf;
+                if function == DwtFunction::Disabled as u32 {
+                    free = Some(index);
+                    break;
+                }
+            }
+            let index = free.ok_or(Error::NoComparator)?;
+            this.write_word(DwtComparator::comp_address(index), address)?;
+            this.write_word(DwtComparator::mask_address(index), size.trailing_zeros())?;
+            this.write_word(
+                DwtComparator::function_address(index),
+                DwtFunction::from(kind) as u32,
+            )?;
+            Ok(index)
+        })
+        .await
+    }
+
+    /// Clear any watchpoints on `address`.
+    pub async fn clear_watchpoint(&mut self, address: u32) -> Result<(), Error> {
+        self.with_attached(|this| {
+            let ctrl = DwtCtrl(this.read_word(DwtCtrl::ADDRESS)?);
+            for index in 0..ctrl.num_comp() {
+                let function = this.read_word(DwtComparator::function_address(index))? & 0xf;
+                let comp = this.read_word(DwtComparator::comp_address(index))?;
+                if function != DwtFunction::Disabled as u32 && comp == address {
+                    this.write_word(
+                        DwtComparator::function_address(index),
+                        DwtFunction::Disabled as u32,
+                    )?;
+                }
+            }
+            Ok(())
+        })
+        .await
+    }
+}
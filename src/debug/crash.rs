@@ -0,0 +1,508 @@
+//! Crash dump capture, persistence and download as an ELF core file.
+//!
+//! [`CrashMonitor`] runs on the monitoring core and watches the other core for a HardFault
+//! (caught by the HardFault vector catch), a lockup or a panic (see [`panic_halt`]). When one
+//! occurs the core registers, the top of the stack and the configured RAM ranges are
+//! captured into an uninitialised RAM buffer and the device is rebooted.
+//!
+//! The RAM buffer survives the watchdog reset, after which the application persists it to
+//! the `CRASH_DUMP` flash region with [`crate::OtaDebugger::persist_crash_dump`]. [`listen`]
+//! serves the persisted dump as an ARM ELF core file, e.g. `nc <device> <port> > core` and
+//! then `arm-none-eabi-gdb <elf> core`.
+use core::mem::{size_of, MaybeUninit};
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use defmt::{debug, info, warn, Format};
+use embassy_net::{driver::Driver, tcp::TcpSocket};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+
+use crate::debug::core_debugger::{CoreDebugger, Error};
+use crate::debug::dap::Core;
+use crate::debug::registers::{CoreRegister, Demcr, Dfsr};
+use crate::reset::ResetReason;
+
+/// The SRAM from which memory ranges can be captured.
+const SRAM: Range<u32> = 0x2000_0000..0x2004_2000;
+
+/// The maximum number of memory ranges captured, including the stack.
+pub const MAX_RANGES: usize = 8;
+/// The number of bytes of memory the dump can hold.
+pub const DATA_CAPACITY: usize = 16 * 1024;
+
+const MAGIC: u32 = 0xc0de_d0d0;
+
+/// The registers captured, in the order of the `pr_reg` field of an ARM `elf_prstatus`.
+const REGISTERS: [CoreRegister; 17] = [
+    CoreRegister::R0,
+    CoreRegister::R1,
+    CoreRegister::R2,
+    CoreRegister::R3,
+    CoreRegister::R4,
+    CoreRegister::R5,
+    CoreRegister::R6,
+    CoreRegister::R7,
+    CoreRegister::R8,
+    CoreRegister::R9,
+    CoreRegister::R10,
+    CoreRegister::R11,
+    CoreRegister::R12,
+    CoreRegister::Sp,
+    CoreRegister::Lr,
+    CoreRegister::Pc,
+    CoreRegister::Xpsr,
+];
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+#[repr(u32)]
+pub enum CrashReason {
+    HardFault = 1,
+    Lockup = 2,
+    Panic = 3,
+}
+
+impl CrashReason {
+    pub(crate) fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            1 => Some(Self::HardFault),
+            2 => Some(Self::Lockup),
+            3 => Some(Self::Panic),
+            _ => None,
+        }
+    }
+
+    /// The signal reported to the debugger.
+    fn signal(&self) -> u16 {
+        match self {
+            Self::HardFault | Self::Lockup => 11, // SIGSEGV
+            Self::Panic => 6,                     // SIGABRT
+        }
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+struct MemoryRange {
+    address: u32,
+    len: u32,
+}
+
+/// The dump as stored in RAM and flash, only the first `len` bytes of `data` are valid.
+#[repr(C)]
+pub(crate) struct CrashDump {
+    magic: u32,
+    checksum: u32,
+    reason: u32,
+    len: u32,
+    registers: [u32; REGISTERS.len()],
+    range_count: u32,
+    ranges: [MemoryRange; MAX_RANGES],
+    data: [u8; DATA_CAPACITY],
+}
+
+const HEADER_SIZE: usize = size_of::<CrashDump>() - DATA_CAPACITY;
+
+impl CrashDump {
+    fn checksum(&self) -> u32 {
+        // FNV-1a over everything following the checksum.
+        let bytes = self.as_bytes();
+        bytes[8..].iter().fold(0x811c_9dc5, |hash: u32, byte| {
+            (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+        })
+    }
+
+    /// Mark the captured dump as valid.
+    fn seal(&mut self) {
+        self.checksum = self.checksum();
+        self.magic = MAGIC;
+    }
+
+    fn is_valid(&self) -> bool {
+        self.magic == MAGIC
+            && self.len as usize <= DATA_CAPACITY
+            && self.range_count as usize <= MAX_RANGES
+            && self.checksum == self.checksum()
+    }
+
+    /// The header and valid data.
+    pub(crate) fn as_bytes(&self) -> &[u8] {
+        let len = HEADER_SIZE + core::cmp::min(self.len as usize, DATA_CAPACITY);
+        // SAFETY: The dump is plain old data.
+        unsafe { core::slice::from_raw_parts(self as *const _ as *const u8, len) }
+    }
+
+    fn ranges(&self) -> impl Iterator<Item = (MemoryRange, &[u8])> {
+        let mut offset = 0;
+        self.ranges[..self.range_count as usize]
+            .iter()
+            .map(move |range| {
+                let data = &self.data[offset..offset + range.len as usize];
+                offset += range.len as usize;
+                (*range, data)
+            })
+    }
+}
+
+#[link_section = ".uninit.crash_dump"]
+static mut RAM_DUMP: MaybeUninit<CrashDump> = MaybeUninit::uninit();
+
+/// Set by [`panic_halt`] before halting.
+static PANICKED: AtomicBool = AtomicBool::new(false);
+
+extern "C" {
+    // Flash offsets of the CRASH_DUMP region, defined in memory.x.
+    static __crash_dump_start: u32;
+    static __crash_dump_end: u32;
+}
+
+/// The flash offsets of the region the dump is persisted to.
+pub(crate) fn flash_region() -> Range<u32> {
+    // SAFETY: Only the addresses of the linker symbols are used.
+    unsafe {
+        let start = &__crash_dump_start as *const u32 as u32;
+        let end = &__crash_dump_end as *const u32 as u32;
+        start..end
+    }
+}
+
+/// The dump captured before the last reset, if any.
+pub(crate) fn ram_dump() -> Option<&'static CrashDump> {
+    // SAFETY: The buffer is only written by the monitor before it reboots the device, and is
+    // only read after it has been validated.
+    let dump = unsafe { (*core::ptr::addr_of!(RAM_DUMP)).assume_init_ref() };
+    dump.is_valid().then_some(dump)
+}
+
+/// Invalidate the dump captured before the last reset, once it has been persisted.
+pub(crate) fn invalidate_ram_dump() {
+    // SAFETY: Only the magic is written, which is valid whether or not the buffer has been
+    // initialised.
+    unsafe { (*core::ptr::addr_of_mut!(RAM_DUMP)).assume_init_mut().magic = 0 };
+}
+
+/// The dump persisted in flash, if any.
+fn flash_dump() -> Option<&'static CrashDump> {
+    let region = flash_region();
+    if region.len() < size_of::<CrashDump>() {
+        return None;
+    }
+    let address = embassy_rp::flash::FLASH_BASE as u32 + region.start;
+    // SAFETY: The region is memory mapped, reserved for the dump and large enough.
+    let dump = unsafe { &*(address as *const CrashDump) };
+    dump.is_valid().then_some(dump)
+}
+
+/// Call from the panic handler of the monitored core, such that the monitor captures a dump.
+/// Requires debug to be enabled by the monitor, otherwise the breakpoint causes a HardFault.
+pub fn panic_halt() -> ! {
+    PANICKED.store(true, Ordering::SeqCst);
+    loop {
+        cortex_m::asm::bkpt();
+    }
+}
+
+pub struct CrashMonitor<CORE: Core> {
+    debugger: CoreDebugger<CORE>,
+    ranges: &'static [Range<u32>],
+    stack_size: u32,
+}
+
+impl<CORE: Core> CrashMonitor<CORE> {
+    /// Monitor the core, capturing `stack_size` bytes above the stack pointer and the given
+    /// SRAM `ranges` (up to [`MAX_RANGES`] - 1) when it crashes.
+    pub async fn new(
+        mut debugger: CoreDebugger<CORE>,
+        ranges: &'static [Range<u32>],
+        stack_size: u32,
+    ) -> Result<Self, Error> {
+        debugger.enable_debug().await?;
+        debugger
+            .with_attached(|debugger| {
+                let mut demcr = Demcr(debugger.read_word(Demcr::ADDRESS)?);
+                demcr.set_vc_harderr(true);
+                debugger.write_word(Demcr::ADDRESS, demcr.0)
+            })
+            .await?;
+        Ok(Self {
+            debugger,
+            ranges,
+            stack_size,
+        })
+    }
+
+    /// Wait for the core to crash, capture a dump and reboot.
+    pub async fn run(mut self) -> ! {
+        loop {
+            let ranges = self.ranges;
+            let stack_size = self.stack_size;
+            match self
+                .debugger
+                .with_attached(|debugger| Self::check(debugger, ranges, stack_size))
+                .await
+            {
+                Ok(Some(reason)) => {
+                    info!("Crash dump captured ({:?}). Rebooting...", reason);
+                    crate::reset::reboot(ResetReason::Crash(reason));
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Crash monitor error: {:?}", e),
+            }
+            Timer::after(Duration::from_millis(10)).await;
+        }
+    }
+
+    fn check(
+        debugger: &mut CoreDebugger<CORE>,
+        ranges: &[Range<u32>],
+        stack_size: u32,
+    ) -> Result<Option<CrashReason>, Error> {
+        let dhcsr = debugger.dhcsr()?;
+        let reason = if dhcsr.s_lockup() {
+            debugger.write_dhcsr(|dhcsr| dhcsr.set_c_halt(true))?;
+            debugger.poll(|debugger| Ok(debugger.dhcsr()?.s_halt()))?;
+            CrashReason::Lockup
+        } else if dhcsr.s_halt() {
+            let dfsr = Dfsr(debugger.read_word(Dfsr::ADDRESS)?);
+            if dfsr.vcatch() {
+                CrashReason::HardFault
+            } else if dfsr.bkpt() && PANICKED.load(Ordering::SeqCst) {
+                CrashReason::Panic
+            } else {
+                // Halted by something else, e.g. a remote debugger.
+                return Ok(None);
+            }
+        } else {
+            return Ok(None);
+        };
+
+        // SAFETY: The dump is only written here, immediately before rebooting.
+        let dump = unsafe { (*core::ptr::addr_of_mut!(RAM_DUMP)).assume_init_mut() };
+        dump.reason = reason as u32;
+        for (value, register) in dump.registers.iter_mut().zip(REGISTERS) {
+            *value = debugger.read_core_register_attached(register)?;
+        }
+
+        let sp = dump.registers[CoreRegister::Sp as usize];
+        let stack = sp..core::cmp::min(sp.saturating_add(stack_size), SRAM.end);
+        let mut len = 0;
+        let mut range_count = 0;
+        for range in core::iter::once(&stack)
+            .chain(ranges.iter())
+            .take(MAX_RANGES)
+        {
+            let start = core::cmp::max(range.start, SRAM.start);
+            let end = core::cmp::min(range.end, SRAM.end);
+            let size = core::cmp::min(end.saturating_sub(start) as usize, DATA_CAPACITY - len);
+            if size == 0 {
+                continue;
+            }
+            // The core is halted and shares the SRAM, so it is read directly.
+            for (i, byte) in dump.data[len..len + size].iter_mut().enumerate() {
+                // SAFETY: The range has been clamped to SRAM.
+                *byte = unsafe { core::ptr::read_volatile((start as usize + i) as *const u8) };
+            }
+            dump.ranges[range_count] = MemoryRange {
+                address: start,
+                len: size as u32,
+            };
+            range_count += 1;
+            len += size;
+        }
+        dump.range_count = range_count as u32;
+        dump.len = len as u32;
+        dump.seal();
+        Ok(Some(reason))
+    }
+}
+
+const ELF_HEADER_SIZE: usize = 52;
+const PROGRAM_HEADER_SIZE: usize = 32;
+const PRSTATUS_SIZE: usize = 148;
+/// Note header, "CORE\0" padded to 8 bytes and the prstatus descriptor.
+const NOTE_SIZE: usize = 12 + 8 + PRSTATUS_SIZE;
+
+fn elf_header(program_headers: u16) -> [u8; ELF_HEADER_SIZE] {
+    let mut header = [0; ELF_HEADER_SIZE];
+    // ELFCLASS32, ELFDATA2LSB, EV_CURRENT
+    header[..7].copy_from_slice(&[0x7f, b'E', b'L', b'F', 1, 1, 1]);
+    header[16..18].copy_from_slice(&4u16.to_le_bytes()); // ET_CORE
+    header[18..20].copy_from_slice(&40u16.to_le_bytes()); // EM_ARM
+    header[20..24].copy_from_slice(&1u32.to_le_bytes()); // EV_CURRENT
+    header[28..32].copy_from_slice(&(ELF_HEADER_SIZE as u32).to_le_bytes()); // e_phoff
+    header[36..40].copy_from_slice(&0x0500_0000u32.to_le_bytes()); // EF_ARM_EABI_VER5
+    header[40..42].copy_from_slice(&(ELF_HEADER_SIZE as u16).to_le_bytes());
+    header[42..44].copy_from_slice(&(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+    header[44..46].copy_from_slice(&program_headers.to_le_bytes());
+    header
+}
+
+fn program_header(
+    kind: u32,
+    offset: usize,
+    address: u32,
+    size: u32,
+    flags: u32,
+) -> [u8; PROGRAM_HEADER_SIZE] {
+    let memory_size = if kind == 1 { size } else { 0 };
+    let fields = [
+        kind,
+        offset as u32,
+        address,
+        address,
+        size,
+        memory_size,
+        flags,
+        4,
+    ];
+    let mut header = [0; PROGRAM_HEADER_SIZE];
+    for (chunk, field) in header.chunks_exact_mut(4).zip(fields) {
+        chunk.copy_from_slice(&field.to_le_bytes());
+    }
+    header
+}
+
+fn note(dump: &CrashDump) -> [u8; NOTE_SIZE] {
+    let mut note = [0; NOTE_SIZE];
+    note[0..4].copy_from_slice(&5u32.to_le_bytes()); // namesz
+    note[4..8].copy_from_slice(&(PRSTATUS_SIZE as u32).to_le_bytes()); // descsz
+    note[8..12].copy_from_slice(&1u32.to_le_bytes()); // NT_PRSTATUS
+    note[12..17].copy_from_slice(b"CORE\0");
+
+    let prstatus = &mut note[20..];
+    let signal = CrashReason::from_u32(dump.reason).map_or(0, |r| r.signal());
+    prstatus[0..4].copy_from_slice(&(signal as u32).to_le_bytes()); // si_signo
+    prstatus[12..14].copy_from_slice(&signal.to_le_bytes()); // pr_cursig
+    prstatus[24..28].copy_from_slice(&1u32.to_le_bytes()); // pr_pid
+
+    // pr_reg: r0-r15, cpsr (xPSR), orig_r0
+    let registers = dump
+        .registers
+        .iter()
+        .chain(core::iter::once(&dump.registers[0]));
+    for (chunk, register) in prstatus[72..144].chunks_exact_mut(4).zip(registers) {
+        chunk.copy_from_slice(&register.to_le_bytes());
+    }
+    note
+}
+
+async fn write_core_file(
+    socket: &mut TcpSocket<'_>,
+    dump: &CrashDump,
+) -> Result<(), embassy_net::tcp::Error> {
+    let range_count = dump.range_count as usize;
+    socket
+        .write_all(&elf_header(1 + range_count as u16))
+        .await?;
+
+    let mut offset = ELF_HEADER_SIZE + PROGRAM_HEADER_SIZE * (1 + range_count);
+    socket
+        .write_all(&program_header(4, offset, 0, NOTE_SIZE as u32, 0)) // PT_NOTE
+        .await?;
+    offset += NOTE_SIZE;
+    for (range, _) in dump.ranges() {
+        // PT_LOAD, PF_R | PF_W
+        socket
+            .write_all(&program_header(1, offset, range.address, range.len, 6))
+            .await?;
+        offset += range.len as usize;
+    }
+
+    socket.write_all(&note(dump)).await?;
+    for (_, data) in dump.ranges() {
+        socket.write_all(data).await?;
+    }
+    socket.flush().await
+}
+
+/// Serve the persisted crash dump as an ELF core file to each client connecting to `port`.
+pub async fn listen(stack: &'static embassy_net::Stack<impl Driver>, port: u16) -> ! {
+    let mut rx_buffer = [0; 64];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(Duration::from_secs(10)));
+
+    loop {
+        if let Err(e) = socket.accept(port).await {
+            warn!("Failed to accept crash dump connection: {:?}", e);
+            continue;
+        }
+        match flash_dump() {
+            Some(dump) => {
+                debug!("Sending crash dump");
+                if let Err(e) = write_core_file(&mut socket, dump).await {
+                    warn!("Write error: {:?}", e);
+                }
+            }
+            None => debug!("No crash dump stored"),
+        }
+        socket.close();
+        let _ = socket.flush().await;
+        socket.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blank_dump() -> Box<CrashDump> {
+        // SAFETY: The dump is plain old data.
+        Box::new(unsafe { core::mem::zeroed() })
+    }
+
+    fn captured_dump() -> Box<CrashDump> {
+        let mut dump = blank_dump();
+        dump.reason = CrashReason::HardFault as u32;
+        dump.registers[15] = 0x1000_0100;
+        dump.ranges[0] = MemoryRange {
+            address: SRAM.start,
+            len: 4,
+        };
+        dump.range_count = 1;
+        dump.data[..4].copy_from_slice(&[1, 2, 3, 4]);
+        dump.len = 4;
+        dump.seal();
+        dump
+    }
+
+    /// Copy the bytes written by `persist_crash_dump` into an otherwise erased region.
+    fn persist(dump: &CrashDump) -> Box<CrashDump> {
+        let mut persisted = blank_dump();
+        let bytes = dump.as_bytes();
+        // SAFETY: `bytes` is no longer than the dump.
+        unsafe {
+            let region = &mut *persisted as *mut CrashDump as *mut u8;
+            core::ptr::write_bytes(region, 0xff, size_of::<CrashDump>());
+            core::ptr::copy_nonoverlapping(bytes.as_ptr(), region, bytes.len());
+        }
+        persisted
+    }
+
+    #[test]
+    fn sealed_dump_is_valid() {
+        assert!(!blank_dump().is_valid());
+        assert!(captured_dump().is_valid());
+    }
+
+    #[test]
+    fn persisted_dump_is_valid() {
+        let dump = captured_dump();
+        assert_eq!(dump.as_bytes().len(), HEADER_SIZE + 4);
+        let persisted = persist(&dump);
+        assert!(persisted.is_valid());
+        let (range, data) = persisted.ranges().next().unwrap();
+        assert_eq!(range.address, SRAM.start);
+        assert_eq!(data, &[1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn corrupted_dump_is_invalid() {
+        let mut dump = captured_dump();
+        dump.data[0] ^= 0xff;
+        assert!(!dump.is_valid());
+        let mut dump = captured_dump();
+        dump.magic = 0;
+        assert!(!dump.is_valid());
+    }
+}
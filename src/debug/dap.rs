@@ -16,7 +16,12 @@ pub struct Dap<CORE> {
     core: CORE,
 }
 
-trait Core {
+/// The dap-rs command processor driving the debug port of `CORE`.
+pub type DapEngine<CORE, LEDS> =
+    dap_rs::dap::Dap<'static, Dap<CORE>, LEDS, embassy_time::Delay, Dap<CORE>, Dap<CORE>, Dap<CORE>>;
+
+/// The debug port of a single core, reached via SYSCFG DBGFORCE.
+pub trait Core {
     fn set_swclk(&mut self, value: bool);
     fn set_swdi(&mut self, value: bool);
     fn swdo(&self) -> bool;
@@ -63,10 +68,7 @@ impl Core for Core1 {
 }
 
 impl Dap<Core0> {
-    pub fn core0<T: DapLeds>(
-        leds: T,
-    ) -> dap_rs::dap::Dap<'static, Dap<Core0>, T, embassy_time::Delay, Dap<Core0>, Dap<Core0>, Dap<Core0>>
-    {
+    pub fn core0<T: DapLeds>(leds: T) -> DapEngine<Core0, T> {
         let inner = Self {
             core: Core0(SYSCFG.dbgforce()),
         };
@@ -75,10 +77,7 @@ impl Dap<Core0> {
 }
 
 impl Dap<Core1> {
-    pub fn core1<T: DapLeds>(
-        leds: T,
-    ) -> dap_rs::dap::Dap<'static, Dap<Core1>, T, embassy_time::Delay, Dap<Core1>, Dap<Core1>, Dap<Core1>>
-    {
+    pub fn core1<T: DapLeds>(leds: T) -> DapEngine<Core1, T> {
         let inner = Self {
             core: Core1(SYSCFG.dbgforce()),
         };
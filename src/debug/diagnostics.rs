@@ -0,0 +1,146 @@
+//! A TCP service answering diagnostic queries about the running application.
+//!
+//! The client sends a one byte command and receives a response (little endian) of:
+//!
+//! | bytes | field |
+//! |-------|-------|
+//! | 1 | command |
+//! | 1 | status: 0 success, 1 unknown command |
+//! | 2 | reserved |
+//! | 4 | payload length |
+//! | n | payload |
+//!
+//! The commands are:
+//!
+//! * [`COMMAND_TASKS`]: the current tick count (8 bytes) followed by a [`TaskInfo`] record for
+//!   each task, see [`TaskInfo::encode`].
+//! * [`COMMAND_STACKS`]: the size and high-water mark (4 bytes each) of the core0 and core1
+//!   stacks, see [`crate::stack`].
+//! * [`COMMAND_FLASH`]: the progress of the flash algorithm, see
+//!   [`crate::FlashProgress::encode`].
+use defmt::{debug, warn};
+use embassy_net::{driver::Driver, tcp::TcpSocket};
+use embassy_time::{Duration, Instant};
+use embedded_io_async::Write;
+
+use crate::debug::tasks::{self, TaskHeaderLayout, TaskInfo, MAX_TASKS, TASK_INFO_SIZE};
+use crate::flash::progress::{self, FLASH_PROGRESS_SIZE};
+use crate::stack;
+
+pub const COMMAND_TASKS: u8 = 0x01;
+pub const COMMAND_STACKS: u8 = 0x02;
+pub const COMMAND_FLASH: u8 = 0x03;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNKNOWN_COMMAND: u8 = 1;
+
+const MAX_PAYLOAD: usize = 8 + MAX_TASKS * TASK_INFO_SIZE;
+
+pub struct Diagnostics {
+    port: u16,
+    layout: TaskHeaderLayout,
+}
+
+impl Default for Diagnostics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Diagnostics {
+    pub fn new() -> Self {
+        Self {
+            port: 1235,
+            layout: TaskHeaderLayout::default(),
+        }
+    }
+
+    pub fn port(&mut self, port: u16) -> &mut Self {
+        self.port = port;
+        self
+    }
+
+    /// Override the task header layout, e.g. for another version of embassy-executor.
+    pub fn task_header_layout(&mut self, layout: TaskHeaderLayout) -> &mut Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Build the payload for `command`, returning its length or `None` if unknown.
+    fn payload(&self, command: u8, payload: &mut [u8; MAX_PAYLOAD]) -> Option<usize> {
+        match command {
+            COMMAND_TASKS => {
+                let mut tasks = [TaskInfo::default(); MAX_TASKS];
+                let count = tasks::scan(&self.layout, &mut tasks);
+                payload[..8].copy_from_slice(&Instant::now().as_ticks().to_le_bytes());
+                for (chunk, task) in payload[8..]
+                    .chunks_exact_mut(TASK_INFO_SIZE)
+                    .zip(&tasks[..count])
+                {
+                    chunk.copy_from_slice(&task.encode());
+                }
+                Some(8 + count * TASK_INFO_SIZE)
+            }
+            COMMAND_STACKS => {
+                let usage = stack::usage();
+                let words = usage.iter().flat_map(|usage| [usage.size, usage.used]);
+                for (chunk, word) in payload.chunks_exact_mut(4).zip(words) {
+                    chunk.copy_from_slice(&word.to_le_bytes());
+                }
+                Some(16)
+            }
+            COMMAND_FLASH => {
+                payload[..FLASH_PROGRESS_SIZE].copy_from_slice(&progress::progress().encode());
+                Some(FLASH_PROGRESS_SIZE)
+            }
+            _ => None,
+        }
+    }
+
+    pub async fn listen(self, stack: &'static embassy_net::Stack<impl Driver>) -> ! {
+        let mut rx_buffer = [0; 64];
+        let mut tx_buffer = [0; 1024];
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        let mut payload = [0; MAX_PAYLOAD];
+
+        loop {
+            debug!("Waiting for diagnostics connection");
+            if let Err(e) = socket.accept(self.port).await {
+                warn!("Failed to accept diagnostics connection: {:?}", e);
+                continue;
+            }
+
+            loop {
+                let mut command = [0];
+                match socket.read(&mut command).await {
+                    Ok(0) => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!("Read error: {:?}", e);
+                        break;
+                    }
+                }
+                let command = command[0];
+                let (status, len) = match self.payload(command, &mut payload) {
+                    Some(len) => (STATUS_OK, len),
+                    None => (STATUS_UNKNOWN_COMMAND, 0),
+                };
+                let mut header = [command, status, 0, 0, 0, 0, 0, 0];
+                header[4..].copy_from_slice(&(len as u32).to_le_bytes());
+                let result = match socket.write_all(&header).await {
+                    Ok(()) => socket.write_all(&payload[..len]).await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = result {
+                    warn!("Write error: {:?}", e);
+                    break;
+                }
+            }
+
+            socket.abort();
+            let _ = socket.flush().await;
+            debug!("Diagnostics connection closed");
+        }
+    }
+}
@@ -1,4 +1,17 @@
+pub mod core_debugger;
+pub mod crash;
 mod dap;
-mod dhcsr;
+pub mod diagnostics;
+pub mod profiler;
+pub mod registers;
+pub mod rtt;
+pub mod semihosting;
 pub mod socket;
 mod status;
+pub mod tasks;
+pub mod tracepoint;
+mod transfer;
+pub mod watch;
+
+pub use dap::{Core0, Core1};
+pub use transfer::{Ack, TransferError};
@@ -0,0 +1,229 @@
+//! Halt-based PC sampling.
+//!
+//! The Cortex-M0+ has no `DWT_PCSR`, so instead the profiled core is briefly halted, its PC,
+//! LR and SP read, and then resumed. Optionally a few callers are recovered by scanning the
+//! stack for return addresses, i.e. odd addresses within `.text` which follow a `BL` or `BLX`.
+//! This is a heuristic: stale return addresses left on the stack may appear as extra frames.
+//!
+//! A client configures the profiler by sending a 4 byte sample interval (µs, little endian),
+//! a 1 byte unwind depth and 3 reserved bytes. Each sample is then sent as an 8 byte timestamp
+//! (µs since boot), a 1 byte frame count, 3 reserved bytes and the frames (4 bytes each), the
+//! PC followed by the callers. `tools/src/bin/profile.rs` folds them for flame graphs.
+use core::ops::Range;
+use core::ptr::read_volatile;
+
+use defmt::{debug, warn};
+use embassy_futures::select::{select, Either};
+use embassy_net::{driver::Driver, tcp::TcpSocket};
+use embassy_time::{Duration, Instant, Ticker};
+use embedded_io_async::Write;
+
+use crate::debug::core_debugger::{CoreDebugger, Error};
+use crate::debug::dap::Core;
+use crate::debug::registers::CoreRegister;
+
+/// The maximum number of frames per sample, including the PC.
+pub const MAX_FRAMES: usize = 16;
+pub const MIN_INTERVAL_US: u32 = 1000;
+/// The maximum size of an encoded [`Sample`].
+pub const MAX_SAMPLE_SIZE: usize = 12 + 4 * MAX_FRAMES;
+
+/// The number of stack words scanned for return addresses.
+const SCAN_WORDS: u32 = 128;
+
+const SRAM: Range<u32> = 0x2000_0000..0x2004_2000;
+
+extern "C" {
+    // The bounds of `.text`, defined by cortex-m-rt.
+    static __stext: u32;
+    static __etext: u32;
+}
+
+fn text() -> Range<u32> {
+    // SAFETY: Only the addresses of the linker symbols are used.
+    unsafe { (&__stext as *const u32 as u32)..(&__etext as *const u32 as u32) }
+}
+
+fn read_halfword(address: u32) -> u16 {
+    // SAFETY: Callers only pass addresses within `.text`.
+    unsafe { read_volatile(address as *const u16) }
+}
+
+/// Whether `value` is plausibly a return address, i.e. a Thumb address within `.text`
+/// immediately following a `BL` or `BLX <reg>`.
+fn is_return_address(value: u32) -> bool {
+    let text = text();
+    let address = value & !1;
+    if value & 1 == 0 || !text.contains(&address) || address < text.start + 4 {
+        return false;
+    }
+    let (first, second) = (read_halfword(address - 4), read_halfword(address - 2));
+    let blx = (second & 0xff87) == 0x4780;
+    let bl = (first & 0xf800) == 0xf000 && (second & 0xd000) == 0xd000;
+    blx || bl
+}
+
+#[derive(Clone, Copy)]
+pub struct Sample {
+    /// Microseconds since boot.
+    pub timestamp: u64,
+    frames: [u32; MAX_FRAMES],
+    len: usize,
+}
+
+impl Sample {
+    /// The PC followed by the callers.
+    pub fn frames(&self) -> &[u32] {
+        &self.frames[..self.len]
+    }
+
+    fn push(&mut self, address: u32) {
+        if self.len < MAX_FRAMES && self.frames()[self.len - 1] != address {
+            self.frames[self.len] = address;
+            self.len += 1;
+        }
+    }
+
+    pub fn encode(&self, data: &mut [u8; MAX_SAMPLE_SIZE]) -> usize {
+        data[..8].copy_from_slice(&self.timestamp.to_le_bytes());
+        data[8..12].copy_from_slice(&[self.len as u8, 0, 0, 0]);
+        for (chunk, frame) in data[12..].chunks_exact_mut(4).zip(self.frames()) {
+            chunk.copy_from_slice(&frame.to_le_bytes());
+        }
+        12 + 4 * self.len
+    }
+}
+
+pub struct Profiler<CORE: Core> {
+    debugger: CoreDebugger<CORE>,
+}
+
+impl<CORE: Core> Profiler<CORE> {
+    pub fn new(debugger: CoreDebugger<CORE>) -> Self {
+        Self { debugger }
+    }
+
+    /// Halt the core, capture up to `depth` frames and resume it. Returns `None` if the core
+    /// was already halted (e.g. by a remote debugger), in which case it is left alone.
+    pub async fn sample(&mut self, depth: usize) -> Result<Option<Sample>, Error> {
+        self.debugger
+            .with_attached(|debugger| {
+                if debugger.dhcsr()?.s_halt() {
+                    return Ok(None);
+                }
+                debugger.write_dhcsr(|dhcsr| dhcsr.set_c_halt(true))?;
+                debugger.poll(|debugger| Ok(debugger.dhcsr()?.s_halt()))?;
+                let timestamp = Instant::now().as_micros();
+                let result = Self::capture(debugger, depth);
+                debugger.write_dhcsr(|_| {})?;
+                result.map(|sample| {
+                    Some(Sample {
+                        timestamp,
+                        ..sample
+                    })
+                })
+            })
+            .await
+    }
+
+    fn capture(debugger: &mut CoreDebugger<CORE>, depth: usize) -> Result<Sample, Error> {
+        let pc = debugger.read_core_register_attached(CoreRegister::Pc)?;
+        let mut sample = Sample {
+            timestamp: 0,
+            frames: [pc; MAX_FRAMES],
+            len: 1,
+        };
+        let depth = core::cmp::min(depth, MAX_FRAMES);
+        if depth <= 1 {
+            return Ok(sample);
+        }
+
+        let lr = debugger.read_core_register_attached(CoreRegister::Lr)?;
+        if is_return_address(lr) {
+            sample.push(lr & !1);
+        }
+        let sp = debugger.read_core_register_attached(CoreRegister::Sp)?;
+        // The core is halted and shares the SRAM, so the stack is read directly.
+        let mut address = sp & !0b11;
+        let end = core::cmp::min(address.saturating_add(4 * SCAN_WORDS), SRAM.end);
+        while SRAM.contains(&address) && address < end && sample.len < depth {
+            // SAFETY: The address is within SRAM.
+            let value = unsafe { read_volatile(address as *const u32) };
+            if is_return_address(value) {
+                sample.push(value & !1);
+            }
+            address += 4;
+        }
+        Ok(sample)
+    }
+
+    /// Stream samples at the rate configured by a TCP client connected to `port`. Sending
+    /// another configuration replaces the current one.
+    pub async fn listen(mut self, stack: &'static embassy_net::Stack<impl Driver>, port: u16) -> ! {
+        let mut rx_buffer = [0; 64];
+        let mut tx_buffer = [0; 8 * MAX_SAMPLE_SIZE];
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+
+        loop {
+            debug!("Waiting for profiler connection");
+            if let Err(e) = socket.accept(port).await {
+                warn!("Failed to accept profiler connection: {:?}", e);
+                continue;
+            }
+
+            let mut depth = None;
+            let mut ticker = Ticker::every(Duration::from_secs(1));
+            let mut config = [0; 8];
+            let mut config_len = 0;
+            let mut data = [0; MAX_SAMPLE_SIZE];
+            loop {
+                match select(socket.read(&mut config[config_len..]), ticker.next()).await {
+                    Either::First(Ok(0)) => break,
+                    Either::First(Ok(n)) => {
+                        config_len += n;
+                        if config_len < config.len() {
+                            continue;
+                        }
+                        config_len = 0;
+                        let [i0, i1, i2, i3, frames, ..] = config;
+                        let interval = u32::from_le_bytes([i0, i1, i2, i3]);
+                        if interval < MIN_INTERVAL_US {
+                            warn!("Profiler interval too short: {}us", interval);
+                            break;
+                        }
+                        debug!("Profiling every {}us, {} frames", interval, frames);
+                        ticker = Ticker::every(Duration::from_micros(interval as u64));
+                        depth = Some(frames as usize);
+                    }
+                    Either::First(Err(e)) => {
+                        warn!("Read error: {:?}", e);
+                        break;
+                    }
+                    Either::Second(()) => {
+                        let Some(depth) = depth else {
+                            continue;
+                        };
+                        let sample = match self.sample(depth).await {
+                            Ok(Some(sample)) => sample,
+                            Ok(None) => continue,
+                            Err(e) => {
+                                warn!("Profiler error: {:?}", e);
+                                continue;
+                            }
+                        };
+                        let len = sample.encode(&mut data);
+                        if let Err(e) = socket.write_all(&data[..len]).await {
+                            warn!("Write error: {:?}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+
+            socket.abort();
+            let _ = socket.flush().await;
+            debug!("Profiler connection closed");
+        }
+    }
+}
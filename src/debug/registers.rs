@@ -0,0 +1,332 @@
+//! Typed models of the ARMv6-M debug registers accessed via the MEM-AP.
+//!
+//! Field positions are taken from the ARMv6-M Architecture Reference Manual (C1.6).
+use defmt::Format;
+
+macro_rules! bit {
+    ($get:ident, $set:ident, $bit:expr) => {
+        pub const fn $get(&self) -> bool {
+            self.0 & (1 << $bit) != 0
+        }
+
+        pub fn $set(&mut self, value: bool) {
+            if value {
+                self.0 |= 1 << $bit;
+            } else {
+                self.0 &= !(1 << $bit);
+            }
+        }
+    };
+}
+
+/// Debug Halting Control and Status Register.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct Dhcsr(pub u32);
+
+impl Dhcsr {
+    pub const ADDRESS: u32 = 0xE000_EDF0;
+    /// Writes are ignored unless the upper half-word contains this key.
+    const DBGKEY: u32 = 0xA05F << 16;
+
+    bit!(c_debugen, set_c_debugen, 0);
+    bit!(c_halt, set_c_halt, 1);
+    bit!(c_step, set_c_step, 2);
+    bit!(c_maskints, set_c_maskints, 3);
+    bit!(s_regrdy, set_s_regrdy, 16);
+    bit!(s_halt, set_s_halt, 17);
+    bit!(s_sleep, set_s_sleep, 18);
+    bit!(s_lockup, set_s_lockup, 19);
+    bit!(s_retire_st, set_s_retire_st, 24);
+    bit!(s_reset_st, set_s_reset_st, 25);
+
+    /// The value to write to the register, the status bits are replaced with the debug key.
+    pub const fn write_value(&self) -> u32 {
+        Self::DBGKEY | (self.0 & 0xffff)
+    }
+}
+
+/// Debug Exception and Monitor Control Register.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct Demcr(pub u32);
+
+impl Demcr {
+    pub const ADDRESS: u32 = 0xE000_EDFC;
+
+    bit!(vc_corereset, set_vc_corereset, 0);
+    bit!(vc_harderr, set_vc_harderr, 10);
+    bit!(dwtena, set_dwtena, 24);
+}
+
+/// A core register selectable via [`Dcrsr`].
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+#[repr(u8)]
+pub enum CoreRegister {
+    R0 = 0,
+    R1 = 1,
+    R2 = 2,
+    R3 = 3,
+    R4 = 4,
+    R5 = 5,
+    R6 = 6,
+    R7 = 7,
+    R8 = 8,
+    R9 = 9,
+    R10 = 10,
+    R11 = 11,
+    R12 = 12,
+    /// The current stack pointer (MSP or PSP).
+    Sp = 13,
+    Lr = 14,
+    /// The debug return address, i.e. the address of the next instruction to execute.
+    Pc = 15,
+    Xpsr = 16,
+    Msp = 17,
+    Psp = 18,
+    /// CONTROL in bits [31:24] and PRIMASK in bits [7:0].
+    ControlPrimask = 20,
+}
+
+/// Debug Core Register Selector Register.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct Dcrsr(pub u32);
+
+impl Dcrsr {
+    pub const ADDRESS: u32 = 0xE000_EDF4;
+
+    bit!(regwnr, set_regwnr, 16);
+
+    pub const fn read(register: CoreRegister) -> Self {
+        Self(register as u32)
+    }
+
+    pub const fn write(register: CoreRegister) -> Self {
+        Self(register as u32 | 1 << 16)
+    }
+
+    pub const fn regsel(&self) -> u8 {
+        (self.0 & 0x1f) as u8
+    }
+}
+
+/// Debug Core Register Data Register.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct Dcrdr(pub u32);
+
+impl Dcrdr {
+    pub const ADDRESS: u32 = 0xE000_EDF8;
+}
+
+/// Application Interrupt and Reset Control Register.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct Aircr(pub u32);
+
+impl Aircr {
+    pub const ADDRESS: u32 = 0xE000_ED0C;
+    /// Writes are ignored unless the upper half-word contains this key.
+    const VECTKEY: u32 = 0x05FA << 16;
+
+    bit!(vectclractive, set_vectclractive, 1);
+    bit!(sysresetreq, set_sysresetreq, 2);
+    bit!(endianness, set_endianness, 15);
+
+    /// The value to write to the register, the read-only bits are replaced with the vector key.
+    pub const fn write_value(&self) -> u32 {
+        Self::VECTKEY | (self.0 & 0x7fff)
+    }
+
+    /// Whether writing `value` to the register requests a system reset.
+    pub const fn requests_reset(value: u32) -> bool {
+        value & 0xffff_0000 == Self::VECTKEY && Self(value).sysresetreq()
+    }
+}
+
+/// Debug Fault Status Register, bits are cleared by writing one.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct Dfsr(pub u32);
+
+impl Dfsr {
+    pub const ADDRESS: u32 = 0xE000_ED30;
+
+    bit!(halted, set_halted, 0);
+    bit!(bkpt, set_bkpt, 1);
+    bit!(dwttrap, set_dwttrap, 2);
+    bit!(vcatch, set_vcatch, 3);
+    bit!(external, set_external, 4);
+
+    /// The value to write to clear all of the set bits.
+    pub const fn clear_value(&self) -> u32 {
+        self.0 & 0x1f
+    }
+}
+
+/// Breakpoint Unit control register (FP_CTRL in ARMv7-M).
+#[derive(Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct BpCtrl(pub u32);
+
+impl BpCtrl {
+    pub const ADDRESS: u32 = 0xE000_2000;
+
+    bit!(enable, set_enable, 0);
+    /// Must be written as one for the write to take effect.
+    bit!(key, set_key, 1);
+
+    pub const fn num_code(&self) -> usize {
+        ((self.0 >> 4) & 0xf) as usize
+    }
+}
+
+/// Breakpoint Unit comparator, matches instruction fetches from the code region.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct BpComp(pub u32);
+
+impl BpComp {
+    pub const fn address(index: usize) -> u32 {
+        0xE000_2008 + 4 * index as u32
+    }
+
+    bit!(enable, set_enable, 0);
+
+    /// A comparator matching the half-word at `address`, which must be below 0x2000_0000.
+    pub const fn breakpoint(address: u32) -> Self {
+        let bp_match = if address & 0b10 == 0 { 0b01 } else { 0b10 };
+        Self(bp_match << 30 | (address & 0x1fff_fffc) | 1)
+    }
+
+    /// The address of the matched half-word.
+    pub const fn comp(&self) -> u32 {
+        let upper = if self.0 >> 30 == 0b10 { 0b10 } else { 0 };
+        (self.0 & 0x1fff_fffc) | upper
+    }
+}
+
+/// Data Watchpoint and Trace control register.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Format)]
+pub struct DwtCtrl(pub u32);
+
+impl DwtCtrl {
+    pub const ADDRESS: u32 = 0xE000_1000;
+
+    pub const fn num_comp(&self) -> usize {
+        (self.0 >> 28) as usize
+    }
+}
+
+/// The access matched by a DWT comparator.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+#[repr(u32)]
+pub enum DwtFunction {
+    Disabled = 0b0000,
+    Pc = 0b0100,
+    Read = 0b0101,
+    Write = 0b0110,
+    ReadWrite = 0b0111,
+}
+
+/// A DWT comparator, consisting of the COMP, MASK and FUNCTION registers.
+pub struct DwtComparator;
+
+impl DwtComparator {
+    pub const fn comp_address(index: usize) -> u32 {
+        0xE000_1020 + 16 * index as u32
+    }
+
+    pub const fn mask_address(index: usize) -> u32 {
+        0xE000_1024 + 16 * index as u32
+    }
+
+    pub const fn function_address(index: usize) -> u32 {
+        0xE000_1028 + 16 * index as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dhcsr() {
+        let mut dhcsr = Dhcsr::default();
+        dhcsr.set_c_debugen(true);
+        assert_eq!(dhcsr.0, 1 << 0);
+        dhcsr.set_c_halt(true);
+        dhcsr.set_c_step(true);
+        dhcsr.set_c_maskints(true);
+        assert_eq!(dhcsr.0, 0b1111);
+        dhcsr.set_c_step(false);
+        assert_eq!(dhcsr.write_value(), 0xa05f_000b);
+
+        let status = Dhcsr(0x0303_0000);
+        assert!(status.s_regrdy());
+        assert!(status.s_halt());
+        assert!(!status.s_sleep());
+        assert!(!status.s_lockup());
+        assert!(status.s_retire_st());
+        assert!(status.s_reset_st());
+        // The status bits are replaced with the key.
+        assert_eq!(status.write_value(), 0xa05f_0000);
+    }
+
+    #[test]
+    fn demcr() {
+        let mut demcr = Demcr::default();
+        demcr.set_vc_corereset(true);
+        assert_eq!(demcr.0, 1 << 0);
+        demcr.set_vc_harderr(true);
+        assert_eq!(demcr.0, 1 << 10 | 1);
+        demcr.set_dwtena(true);
+        assert_eq!(demcr.0, 1 << 24 | 1 << 10 | 1);
+        demcr.set_vc_corereset(false);
+        assert_eq!(demcr.0, 1 << 24 | 1 << 10);
+        assert_eq!(Demcr::ADDRESS, 0xe000_edfc);
+    }
+
+    #[test]
+    fn dfsr() {
+        let dfsr = Dfsr(0xffff_ffe5);
+        assert!(dfsr.halted());
+        assert!(!dfsr.bkpt());
+        assert!(dfsr.dwttrap());
+        assert!(!dfsr.vcatch());
+        assert!(!dfsr.external());
+        assert_eq!(dfsr.clear_value(), 0b00101);
+
+        let mut dfsr = Dfsr::default();
+        dfsr.set_bkpt(true);
+        dfsr.set_vcatch(true);
+        dfsr.set_external(true);
+        assert_eq!(dfsr.0, 0b11010);
+        assert_eq!(Dfsr::ADDRESS, 0xe000_ed30);
+    }
+
+    #[test]
+    fn dcrsr() {
+        assert_eq!(Dcrsr::read(CoreRegister::Pc).0, 15);
+        assert_eq!(Dcrsr::write(CoreRegister::Xpsr).0, 1 << 16 | 16);
+        assert!(Dcrsr::write(CoreRegister::R0).regwnr());
+        assert_eq!(Dcrsr::write(CoreRegister::ControlPrimask).regsel(), 20);
+    }
+
+    #[test]
+    fn aircr() {
+        let mut aircr = Aircr(0xfa05_8000);
+        assert!(aircr.endianness());
+        aircr.set_sysresetreq(true);
+        // The read-only key and endianness are replaced with the vector key.
+        assert_eq!(aircr.write_value(), 0x05fa_0004);
+        assert!(Aircr::requests_reset(aircr.write_value()));
+        assert!(!Aircr::requests_reset(0x05fa_0002));
+        assert!(!Aircr::requests_reset(0x0000_0004));
+    }
+
+    #[test]
+    fn breakpoint_comparators() {
+        let lower = BpComp::breakpoint(0x1000_0100);
+        assert_eq!(lower.0, 0b01 << 30 | 0x1000_0100 | 1);
+        assert_eq!(lower.comp(), 0x1000_0100);
+        let upper = BpComp::breakpoint(0x1000_0102);
+        assert_eq!(upper.0, 0b10 << 30 | 0x1000_0100 | 1);
+        assert_eq!(upper.comp(), 0x1000_0102);
+        assert_eq!(BpComp::address(3), 0xe000_2014);
+        assert_eq!(BpCtrl(0x0000_0040).num_code(), 4);
+    }
+}
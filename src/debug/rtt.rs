@@ -0,0 +1,259 @@
+//! Bridge between the SEGGER RTT channels of the application and TCP.
+//!
+//! Both cores share the SRAM, so the RTT control block (e.g. the one placed by `defmt-rtt`)
+//! can be accessed directly from this core. Up channels are drained and down channels filled
+//! exactly as a probe would, but without halting the application core or attaching a debugger.
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+
+use defmt::{debug, trace, warn};
+use embassy_futures::select::{select, Either};
+use embassy_net::{driver::Driver, tcp::TcpSocket};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+
+/// The SRAM (including the striped and unstriped banks) searched for the control block.
+const SRAM: core::ops::Range<u32> = 0x2000_0000..0x2004_2000;
+
+/// The control block identifier, split so that this constant is never mistaken for it.
+const ID_PREFIX: &[u8] = b"SEGGER";
+const ID_SUFFIX: &[u8] = b" RTT\0\0\0\0\0\0";
+const ID_SIZE: u32 = 16;
+
+/// Offsets of the fields of a channel descriptor.
+const DESCRIPTOR_SIZE: u32 = 24;
+const BUFFER_OFFSET: u32 = 4;
+const SIZE_OFFSET: u32 = 8;
+const WRITE_OFFSET: u32 = 12;
+const READ_OFFSET: u32 = 16;
+
+/// How often the channels are polled whilst a client is connected.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+fn read_word(address: u32) -> u32 {
+    // SAFETY: Callers only pass addresses validated to be within SRAM.
+    unsafe { read_volatile(address as *const u32) }
+}
+
+fn write_word(address: u32, value: u32) {
+    // SAFETY: Callers only pass addresses validated to be within SRAM.
+    unsafe { write_volatile(address as *mut u32, value) }
+}
+
+fn in_sram(address: u32, len: u32) -> bool {
+    SRAM.contains(&address) && address.checked_add(len).is_some_and(|end| end <= SRAM.end)
+}
+
+/// The RTT control block of the application.
+#[derive(Clone, Copy)]
+pub struct ControlBlock {
+    address: u32,
+}
+
+impl ControlBlock {
+    /// Search SRAM for an initialised control block.
+    pub fn locate() -> Option<Self> {
+        let found = SRAM
+            .step_by(4)
+            .find(|address| in_sram(*address, ID_SIZE) && Self::matches_id(*address))?;
+        debug!("RTT control block found at {:#x}", found);
+        Some(Self { address: found })
+    }
+
+    fn matches_id(address: u32) -> bool {
+        let id = ID_PREFIX.iter().chain(ID_SUFFIX.iter());
+        id.enumerate().all(|(i, expected)| {
+            // SAFETY: The identifier is within SRAM, checked by the caller.
+            let byte = unsafe { read_volatile((address + i as u32) as *const u8) };
+            byte == *expected
+        })
+    }
+
+    pub fn up_channels(&self) -> usize {
+        read_word(self.address + ID_SIZE) as usize
+    }
+
+    pub fn down_channels(&self) -> usize {
+        read_word(self.address + ID_SIZE + 4) as usize
+    }
+
+    fn descriptor(&self, index: usize) -> u32 {
+        self.address + ID_SIZE + 8 + DESCRIPTOR_SIZE * index as u32
+    }
+
+    /// The up and down channels with the given index, either of which may be absent.
+    pub fn channel(&self, index: usize) -> Channel {
+        let up = (index < self.up_channels())
+            .then(|| RingBuffer::new(self.descriptor(index)))
+            .flatten();
+        let down = (index < self.down_channels())
+            .then(|| RingBuffer::new(self.descriptor(self.up_channels() + index)))
+            .flatten();
+        Channel { up, down }
+    }
+}
+
+/// A channel descriptor, the buffer is written by one side and read by the other.
+struct RingBuffer {
+    descriptor: u32,
+    buffer: u32,
+    size: u32,
+}
+
+impl RingBuffer {
+    fn new(descriptor: u32) -> Option<Self> {
+        if !in_sram(descriptor, DESCRIPTOR_SIZE) {
+            return None;
+        }
+        let buffer = read_word(descriptor + BUFFER_OFFSET);
+        let size = read_word(descriptor + SIZE_OFFSET);
+        if size == 0 || !in_sram(buffer, size) {
+            warn!("Invalid RTT channel descriptor at {:#x}", descriptor);
+            return None;
+        }
+        Some(Self {
+            descriptor,
+            buffer,
+            size,
+        })
+    }
+
+    fn offsets(&self) -> (u32, u32) {
+        let write = read_word(self.descriptor + WRITE_OFFSET);
+        let read = read_word(self.descriptor + READ_OFFSET);
+        (write % self.size, read % self.size)
+    }
+
+    fn copy_from(&self, offset: u32, data: &mut [u8]) {
+        for (i, byte) in data.iter_mut().enumerate() {
+            let address = self.buffer + (offset + i as u32) % self.size;
+            // SAFETY: The buffer is within SRAM, checked on construction.
+            *byte = unsafe { read_volatile(address as *const u8) };
+        }
+    }
+
+    fn copy_to(&self, offset: u32, data: &[u8]) {
+        for (i, byte) in data.iter().enumerate() {
+            let address = self.buffer + (offset + i as u32) % self.size;
+            // SAFETY: The buffer is within SRAM, checked on construction.
+            unsafe { write_volatile(address as *mut u8, *byte) };
+        }
+    }
+
+    /// Drain up to `data.len()` bytes written by the application.
+    fn read(&mut self, data: &mut [u8]) -> usize {
+        let (write, read) = self.offsets();
+        let available = (write + self.size - read) % self.size;
+        let n = core::cmp::min(available as usize, data.len());
+        // Ensure the data is read after the write offset.
+        fence(Ordering::SeqCst);
+        self.copy_from(read, &mut data[..n]);
+        fence(Ordering::SeqCst);
+        write_word(self.descriptor + READ_OFFSET, (read + n as u32) % self.size);
+        n
+    }
+
+    /// Write as much of `data` as fits, leaving one byte free to distinguish full from empty.
+    fn write(&mut self, data: &[u8]) -> usize {
+        let (write, read) = self.offsets();
+        let free = (read + self.size - write - 1) % self.size;
+        let n = core::cmp::min(free as usize, data.len());
+        self.copy_to(write, &data[..n]);
+        // Ensure the data is written before the write offset.
+        fence(Ordering::SeqCst);
+        write_word(self.descriptor + WRITE_OFFSET, (write + n as u32) % self.size);
+        n
+    }
+}
+
+/// An up (application to host) and down (host to application) channel pair.
+pub struct Channel {
+    up: Option<RingBuffer>,
+    down: Option<RingBuffer>,
+}
+
+impl Channel {
+    pub fn has_up(&self) -> bool {
+        self.up.is_some()
+    }
+
+    pub fn has_down(&self) -> bool {
+        self.down.is_some()
+    }
+
+    /// Expose the channel to a TCP client connected to `port`. Data received from the client
+    /// is written to the down channel, data written to the up channel is sent to the client.
+    /// Up channel data is left in place whilst no client is connected.
+    pub async fn listen(
+        mut self,
+        stack: &'static embassy_net::Stack<impl Driver>,
+        port: u16,
+    ) -> ! {
+        let mut rx_buffer = [0; 256];
+        let mut tx_buffer = [0; 1024];
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+
+        loop {
+            debug!("Waiting for RTT connection on port {}", port);
+            if let Err(e) = socket.accept(port).await {
+                warn!("Failed to accept RTT connection: {:?}", e);
+                continue;
+            }
+            debug!("RTT connected on port {}", port);
+
+            let mut pending = [0; 256];
+            let mut pending_len = 0;
+            'connection: loop {
+                if let Some(up) = self.up.as_mut() {
+                    let mut data = [0; 256];
+                    loop {
+                        let n = up.read(&mut data);
+                        if n == 0 {
+                            break;
+                        }
+                        trace!("RTT up {} bytes", n);
+                        if let Err(e) = socket.write_all(&data[..n]).await {
+                            warn!("Write error: {:?}", e);
+                            break 'connection;
+                        }
+                    }
+                }
+
+                if let Some(down) = self.down.as_mut() {
+                    let n = down.write(&pending[..pending_len]);
+                    pending.copy_within(n..pending_len, 0);
+                    pending_len -= n;
+                }
+
+                if pending_len == pending.len() {
+                    // The application isn't draining the down channel, apply backpressure.
+                    Timer::after(POLL_INTERVAL).await;
+                    continue;
+                }
+                match select(
+                    socket.read(&mut pending[pending_len..]),
+                    Timer::after(POLL_INTERVAL),
+                )
+                .await
+                {
+                    Either::First(Ok(0)) => break,
+                    Either::First(Ok(n)) => {
+                        trace!("RTT down {} bytes", n);
+                        if self.down.is_some() {
+                            pending_len += n;
+                        }
+                    }
+                    Either::First(Err(e)) => {
+                        warn!("Read error: {:?}", e);
+                        break;
+                    }
+                    Either::Second(()) => {}
+                }
+            }
+
+            socket.abort();
+            let _ = socket.flush().await;
+            debug!("RTT connection closed on port {}", port);
+        }
+    }
+}
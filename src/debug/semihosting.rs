@@ -0,0 +1,269 @@
+//! Semihosting serviced locally rather than by a host debugger.
+//!
+//! When the debugged core executes `BKPT 0xAB` it halts with the operation in R0 and its
+//! parameter in R1. [`Semihosting`] detects these halts, performs the operation, writes the
+//! result to R0 and resumes the core past the breakpoint instruction. Output is forwarded to
+//! a TCP console, allowing firmware using `cortex-m-semihosting` to run without a probe.
+//!
+//! The supported operations are those needed for console output, timing and exit reporting:
+//! `SYS_OPEN` (`":tt"` only), `SYS_CLOSE`, `SYS_WRITEC`, `SYS_WRITE0`, `SYS_WRITE`, `SYS_CLOCK`,
+//! `SYS_TIME`, `SYS_EXIT` and `SYS_EXIT_EXTENDED`. Any other operation returns -1.
+use defmt::{debug, trace, warn, Format};
+use embassy_futures::select::{select, Either};
+use embassy_net::{driver::Driver, tcp::TcpSocket};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::Write;
+
+use crate::debug::core_debugger::{CoreDebugger, Error};
+use crate::debug::dap::Core;
+use crate::debug::registers::{CoreRegister, Dfsr};
+
+/// The maximum number of bytes output per operation. `SYS_WRITE` reports the remainder as
+/// unwritten so that the caller retries, `SYS_WRITE0` is truncated.
+pub const MAX_OUTPUT: usize = 256;
+
+const BKPT_SEMIHOSTING: u16 = 0xbeab;
+
+const SYS_OPEN: u32 = 0x01;
+const SYS_CLOSE: u32 = 0x02;
+const SYS_WRITEC: u32 = 0x03;
+const SYS_WRITE0: u32 = 0x04;
+const SYS_WRITE: u32 = 0x05;
+const SYS_CLOCK: u32 = 0x10;
+const SYS_TIME: u32 = 0x11;
+const SYS_EXIT: u32 = 0x18;
+const SYS_EXIT_EXTENDED: u32 = 0x20;
+
+/// `ADP_Stopped_ApplicationExit`, the reason reported for a successful exit.
+pub const EXIT_SUCCESS: u32 = 0x20026;
+
+/// The file name used to open the console.
+const CONSOLE: &[u8] = b":tt";
+const STDOUT: u32 = 1;
+const STDERR: u32 = 2;
+const FAILURE: u32 = u32::MAX;
+
+/// The interval between checks for a request, doubled up to the maximum whilst there are none.
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The result of servicing a semihosting request.
+#[derive(Format)]
+pub enum Event {
+    /// Console output.
+    Output { data: [u8; MAX_OUTPUT], len: usize },
+    /// The application exited with the given reason, the core is left halted. This is the
+    /// last event.
+    Exit { reason: u32, subcode: u32 },
+    /// A request that produced no output.
+    Handled,
+}
+
+pub struct Semihosting<CORE: Core> {
+    debugger: CoreDebugger<CORE>,
+    exited: bool,
+}
+
+impl<CORE: Core> Semihosting<CORE> {
+    /// Enable debug on the core, such that semihosting breakpoints halt rather than fault.
+    pub async fn new(mut debugger: CoreDebugger<CORE>) -> Result<Self, Error> {
+        debugger.enable_debug().await?;
+        Ok(Self {
+            debugger,
+            exited: false,
+        })
+    }
+
+    /// Wait for a semihosting request and service it. The core has been resumed before
+    /// this returns (unless it exited), it is safe to cancel. Once the application has exited
+    /// the core is no longer polled and this never returns.
+    pub async fn next(&mut self) -> Result<Event, Error> {
+        if self.exited {
+            return core::future::pending().await;
+        }
+        let mut interval = MIN_POLL_INTERVAL;
+        loop {
+            if let Some(event) = self.debugger.with_attached(Self::service).await? {
+                self.exited = matches!(event, Event::Exit { .. });
+                return Ok(event);
+            }
+            Timer::after(interval).await;
+            interval = core::cmp::min(interval * 2, MAX_POLL_INTERVAL);
+        }
+    }
+
+    fn service(debugger: &mut CoreDebugger<CORE>) -> Result<Option<Event>, Error> {
+        if !debugger.dhcsr()?.s_halt() {
+            return Ok(None);
+        }
+        let dfsr = Dfsr(debugger.read_word(Dfsr::ADDRESS)?);
+        if !dfsr.bkpt() {
+            return Ok(None);
+        }
+        let pc = debugger.read_core_register_attached(CoreRegister::Pc)?;
+        let mut instruction = [0; 2];
+        debugger.read_bytes_attached(pc, &mut instruction)?;
+        if u16::from_le_bytes(instruction) != BKPT_SEMIHOSTING {
+            return Ok(None);
+        }
+        debugger.write_word(Dfsr::ADDRESS, dfsr.clear_value())?;
+
+        let operation = debugger.read_core_register_attached(CoreRegister::R0)?;
+        let parameter = debugger.read_core_register_attached(CoreRegister::R1)?;
+        trace!("Semihosting operation {:#x}, parameter {:#x}", operation, parameter);
+
+        let mut data = [0; MAX_OUTPUT];
+        let (result, event) = match operation {
+            SYS_OPEN => {
+                // Parameter block: name pointer, mode, name length.
+                let [name, mode, len] = Self::read_block(debugger, parameter)?;
+                let mut buffer = [0; CONSOLE.len()];
+                let handle = if len as usize == CONSOLE.len() {
+                    debugger.read_bytes_attached(name, &mut buffer)?;
+                    // Modes 0-3 are read, 4-7 write and 8-11 append.
+                    match (buffer == CONSOLE, mode) {
+                        (true, 4..=7) => STDOUT,
+                        (true, 8..=11) => STDERR,
+                        _ => FAILURE,
+                    }
+                } else {
+                    FAILURE
+                };
+                (handle, Event::Handled)
+            }
+            SYS_CLOSE => (0, Event::Handled),
+            SYS_WRITEC => {
+                debugger.read_bytes_attached(parameter, &mut data[..1])?;
+                (0, Event::Output { data, len: 1 })
+            }
+            SYS_WRITE0 => {
+                let mut len = 0;
+                while len < MAX_OUTPUT {
+                    let end = core::cmp::min(len + 4, MAX_OUTPUT);
+                    debugger.read_bytes_attached(parameter + len as u32, &mut data[len..end])?;
+                    if let Some(nul) = data[len..end].iter().position(|b| *b == 0) {
+                        len += nul;
+                        break;
+                    }
+                    len = end;
+                }
+                (0, Event::Output { data, len })
+            }
+            SYS_WRITE => {
+                // Parameter block: handle, data pointer, length.
+                let [handle, pointer, requested] = Self::read_block(debugger, parameter)?;
+                if handle == STDOUT || handle == STDERR {
+                    let len = core::cmp::min(requested as usize, MAX_OUTPUT);
+                    debugger.read_bytes_attached(pointer, &mut data[..len])?;
+                    // Returns the number of bytes not written.
+                    (requested - len as u32, Event::Output { data, len })
+                } else {
+                    (requested, Event::Handled)
+                }
+            }
+            SYS_CLOCK => ((Instant::now().as_millis() / 10) as u32, Event::Handled),
+            // There is no real-time clock, report the time since boot.
+            SYS_TIME => (Instant::now().as_secs() as u32, Event::Handled),
+            SYS_EXIT => {
+                debug!("Semihosting exit: {:#x}", parameter);
+                return Ok(Some(Event::Exit {
+                    reason: parameter,
+                    subcode: 0,
+                }));
+            }
+            SYS_EXIT_EXTENDED => {
+                let [reason, subcode] = Self::read_block(debugger, parameter)?;
+                debug!("Semihosting exit: {:#x} ({})", reason, subcode);
+                return Ok(Some(Event::Exit { reason, subcode }));
+            }
+            _ => {
+                warn!("Unsupported semihosting operation {:#x}", operation);
+                (FAILURE, Event::Handled)
+            }
+        };
+
+        debugger.write_core_register_attached(CoreRegister::R0, result)?;
+        debugger.write_core_register_attached(CoreRegister::Pc, pc + 2)?;
+        debugger.write_dhcsr(|_| {})?;
+        Ok(Some(event))
+    }
+
+    fn read_block<const N: usize>(
+        debugger: &mut CoreDebugger<CORE>,
+        address: u32,
+    ) -> Result<[u32; N], Error> {
+        let mut block = [0; N];
+        for (i, word) in block.iter_mut().enumerate() {
+            *word = debugger.read_word(address + 4 * i as u32)?;
+        }
+        Ok(block)
+    }
+
+    /// Service requests, forwarding output to a TCP client connected to `port`. Output is
+    /// discarded whilst no client is connected, which is disconnected once the application
+    /// exits.
+    pub async fn listen(
+        mut self,
+        stack: &'static embassy_net::Stack<impl Driver>,
+        port: u16,
+    ) -> ! {
+        let mut rx_buffer = [0; 64];
+        let mut tx_buffer = [0; 2 * MAX_OUTPUT];
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+
+        loop {
+            debug!("Waiting for console connection");
+            {
+                let accept = socket.accept(port);
+                let mut accept = core::pin::pin!(accept);
+                loop {
+                    match select(&mut accept, self.next()).await {
+                        Either::First(Ok(())) => break,
+                        Either::First(Err(e)) => {
+                            warn!("Failed to accept console connection: {:?}", e);
+                            break;
+                        }
+                        Either::Second(Ok(_)) => {}
+                        Either::Second(Err(e)) => {
+                            warn!("Semihosting error: {:?}", e);
+                            Timer::after(MAX_POLL_INTERVAL).await;
+                        }
+                    }
+                }
+            }
+
+            while socket.state() == embassy_net::tcp::State::Established {
+                let result = match self.next().await {
+                    Ok(Event::Output { data, len }) => socket.write_all(&data[..len]).await,
+                    Ok(Event::Exit { reason, .. }) => {
+                        let status: &[u8] = if reason == EXIT_SUCCESS {
+                            b"\n[exit: success]\n"
+                        } else {
+                            b"\n[exit: failure]\n"
+                        };
+                        if let Err(e) = socket.write_all(status).await {
+                            warn!("Write error: {:?}", e);
+                        }
+                        break;
+                    }
+                    Ok(Event::Handled) => Ok(()),
+                    Err(e) => {
+                        warn!("Semihosting error: {:?}", e);
+                        // Back off rather than retrying a persistent error immediately.
+                        Timer::after(MAX_POLL_INTERVAL).await;
+                        Ok(())
+                    }
+                };
+                if let Err(e) = result {
+                    warn!("Write error: {:?}", e);
+                    break;
+                }
+            }
+
+            socket.abort();
+            let _ = socket.flush().await;
+            debug!("Console connection closed");
+        }
+    }
+}
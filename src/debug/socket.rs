@@ -1,24 +1,85 @@
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use crate::debug::dap::Dap;
-use crate::debug::dhcsr::DHCSR_CLEAR_DEBUGEN;
+use crate::debug::registers::{BpCtrl, Dhcsr};
 use crate::debug::status::DebugStatus;
+use crate::debug::transfer::{requests_reset, Transfer, TransferResponse};
 use crate::flash::algorithm::INIT_CALLED;
 use crate::flash::spinlock::with_spinlock;
-use cortex_m::asm::nop;
+use crate::reset::{self, ResetReason};
 use dap_rs::dap::DapVersion;
 use defmt::{debug, trace, warn};
+use embassy_futures::select::{select, Either};
 use embassy_net::{driver::Driver, tcp::TcpSocket};
-use embassy_rp::watchdog::Watchdog;
-use embassy_rp::Peripherals;
-use embassy_time::Duration;
+use embassy_time::{Duration, Instant, Timer};
 use embedded_io_async::Write;
 
 const PACKET_SIZE: usize = dap_rs::usb::DAP2_PACKET_SIZE as usize;
 
+static LISTENING: AtomicBool = AtomicBool::new(false);
+
+/// Set once the flash algorithm has been run, until the device reboots.
+static UPDATE_PENDING: AtomicBool = AtomicBool::new(false);
+static REBOOT_APPROVED: AtomicBool = AtomicBool::new(false);
+
+/// When to reboot after the flash algorithm has been run, e.g. to apply an update.
+#[derive(Clone, Copy)]
+pub enum RebootPolicy {
+    /// As soon as the debug connection closes.
+    Immediate,
+    /// Once approved with [`crate::OtaDebugger::approve_reboot`].
+    Approved,
+    /// Once the callback returns `true`. It is polled (on the core running the debug server)
+    /// whilst the update is pending.
+    Callback(fn() -> bool),
+    /// At the given time, e.g. the start of a maintenance window.
+    Scheduled(Instant),
+}
+
+impl RebootPolicy {
+    fn ready(&self) -> bool {
+        match self {
+            Self::Immediate => true,
+            // Consumed by the reboot it allows.
+            Self::Approved => REBOOT_APPROVED.swap(false, Ordering::Relaxed),
+            Self::Callback(ready) => ready(),
+            Self::Scheduled(at) => Instant::now() >= *at,
+        }
+    }
+}
+
+pub(crate) fn update_pending() -> bool {
+    UPDATE_PENDING.load(Ordering::Relaxed)
+}
+
+pub(crate) fn approve_reboot() {
+    REBOOT_APPROVED.store(true, Ordering::Relaxed);
+}
+
+/// Discard any approval, as a new image is being flashed.
+pub(crate) fn revoke_reboot() {
+    REBOOT_APPROVED.store(false, Ordering::Relaxed);
+}
+
+/// Reboot to apply the update. With `direct-flash`, the staged image is first copied over the
+/// running one.
+async fn apply_update() -> ! {
+    #[cfg(feature = "direct-flash")]
+    crate::flash::direct::apply().await;
+    #[cfg(not(feature = "direct-flash"))]
+    reset::reboot(ResetReason::Ota)
+}
+
+/// Whether the debug server is accepting connections, e.g. for a health check during
+/// [`crate::OtaDebugger::trial_boot`].
+pub fn is_listening() -> bool {
+    LISTENING.load(Ordering::Relaxed)
+}
+
 pub struct DebugSocket {
     port: u16,
     timeout: Option<Duration>,
+    reboot_policy: RebootPolicy,
 }
 
 impl DebugSocket {
@@ -26,6 +87,7 @@ impl DebugSocket {
         Self {
             port: 1234,
             timeout: Some(Duration::from_secs(10)),
+            reboot_policy: RebootPolicy::Immediate,
         }
     }
 
@@ -39,11 +101,29 @@ impl DebugSocket {
         self
     }
 
+    pub fn reboot_policy(&mut self, reboot_policy: RebootPolicy) -> &mut Self {
+        self.reboot_policy = reboot_policy;
+        self
+    }
+
+    /// Reboot once the update is pending and the policy allows it. Connections are still
+    /// accepted in the meantime.
+    async fn reboot_when_ready(&self) -> ! {
+        loop {
+            if update_pending() && self.reboot_policy.ready() {
+                debug!("Flash algorithm detected. Rebooting...");
+                apply_update().await;
+            }
+            Timer::after_millis(100).await;
+        }
+    }
+
     pub async fn listen(self, stack: &'static embassy_net::Stack<impl Driver>) -> ! {
         let mut rx_buffer = [0; PACKET_SIZE];
         let mut tx_buffer = [0; PACKET_SIZE];
         let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
         socket.set_timeout(self.timeout);
+        LISTENING.store(true, Ordering::Relaxed);
 
         loop {
             let debug_status = DebugStatus::default();
@@ -51,7 +131,11 @@ impl DebugSocket {
 
             debug!("Waiting for connection");
 
-            if socket.accept(self.port).await.is_err() {
+            let accepted = match select(socket.accept(self.port), self.reboot_when_ready()).await {
+                Either::First(accepted) => accepted,
+                Either::Second(never) => never,
+            };
+            if accepted.is_err() {
                 warn!("Failed to accept connection");
                 continue;
             }
@@ -79,6 +163,11 @@ impl DebugSocket {
 
                         trace!("Received {} bytes, command {}", n, request_buffer[0]);
 
+                        if requests_reset(&request_buffer[..n]) {
+                            debug!("Reset requested by the debugger");
+                            reset::record(ResetReason::Debugger, 0);
+                        }
+
                         let mut response_buffer = [0; dap_rs::usb::DAP2_PACKET_SIZE as usize];
                         let n = dap.process_command(
                             &request_buffer[..n],
@@ -97,14 +186,27 @@ impl DebugSocket {
                         };
 
                         if !debug_status.disconnected() {
+                            let mut request_buffer = [0; dap_rs::usb::DAP2_PACKET_SIZE as usize];
+                            let mut transfer = Transfer::new(&mut request_buffer);
+                            // Breakpoints left enabled would escalate to a HardFault once
+                            // released, tracepoints are re-armed by their `Tracer`.
+                            let mut bp_ctrl = BpCtrl::default();
+                            bp_ctrl.set_key(true);
+                            transfer.write_memory(BpCtrl::ADDRESS, bp_ctrl.0);
+                            // Writing DHCSR without C_DEBUGEN releases the core from debug.
+                            transfer.write_memory(Dhcsr::ADDRESS, Dhcsr::default().write_value());
+                            let expected = transfer.count();
+                            let request = transfer.finish();
+
                             let mut response_buffer = [0; dap_rs::usb::DAP2_PACKET_SIZE as usize];
-                            let n = dap.process_command(
-                                &DHCSR_CLEAR_DEBUGEN,
-                                &mut response_buffer,
-                                DapVersion::V2,
-                            );
+                            let n =
+                                dap.process_command(request, &mut response_buffer, DapVersion::V2);
                             trace!("Responding with {}", response_buffer[..n]);
-                            // TODO assert success?
+                            if let Err(e) =
+                                TransferResponse::parse(&response_buffer[..n], expected)
+                            {
+                                warn!("Failed to clear C_DEBUGEN: {:?}", e);
+                            }
                             break;
                         }
                     }
@@ -127,21 +229,41 @@ impl DebugSocket {
 
             debug!("Connection closed");
 
-            if INIT_CALLED.load(Ordering::SeqCst) {
+            if self.session_ended() {
                 debug!("Flash algorithm detected. Rebooting...");
-                reboot();
+                apply_update().await;
             }
         }
     }
+
+    /// Mark the update pending if the connection ran the flash algorithm, returning whether to
+    /// apply it now. Later connections which don't flash leave it to [`Self::reboot_when_ready`].
+    fn session_ended(&self) -> bool {
+        if !INIT_CALLED.swap(false, Ordering::SeqCst) {
+            return false;
+        }
+        debug!("Flash progress: {:?}", crate::flash::progress::progress());
+        crate::flash::progress::end_session();
+        UPDATE_PENDING.store(true, Ordering::Relaxed);
+        if self.reboot_policy.ready() {
+            return true;
+        }
+        debug!("Flash algorithm detected. Reboot deferred");
+        false
+    }
 }
 
-fn reboot() -> ! {
-    // Safety: This will reboot the device.
-    let p = unsafe { Peripherals::steal() };
-    let mut watchdog = Watchdog::new(p.WATCHDOG);
-    watchdog.trigger_reset();
-    // Not sure why trigger_reset doesn't return !, so we loop here.
-    loop {
-        nop();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_flashing_connection_reboots() {
+        let socket = DebugSocket::new();
+        INIT_CALLED.store(true, Ordering::SeqCst);
+        assert!(socket.session_ended());
+        assert!(update_pending());
+        // A later connection which doesn't flash, e.g. to read RTT.
+        assert!(!socket.session_ended());
     }
 }
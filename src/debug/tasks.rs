@@ -0,0 +1,228 @@
+//! Introspection of the embassy executors' tasks.
+//!
+//! The executors are registered by [`crate::OtaDebugger::new`], by spawning a task on each whose
+//! header identifies the executor. embassy-executor keeps no list of its tasks (only of those
+//! queued to run or waiting for a timer), so their headers are found by scanning `.data` and
+//! `.bss` (which hold the task arena and any static task pools) for words which point back at a
+//! registered executor and at a poll function within `.text`. Each header's state and timer
+//! expiry are then read directly, without halting the core.
+//!
+//! The scan is heuristic: other data which happens to look like a spawned task's header (e.g. a
+//! copy of one) is reported as a task too.
+//!
+//! The task header isn't `repr(C)`, so its layout is described by [`TaskHeaderLayout`]. The
+//! default matches embassy-executor 0.6.3 (the version required by this crate) built for
+//! `thumbv6m`, with or without `integrated-timers` as measured from the size of the header; it
+//! can be checked with e.g. `ptype/o embassy_executor::raw::TaskHeader` in GDB.
+use core::future::{Future, Pending};
+use core::mem::size_of;
+use core::ops::Range;
+use core::pin::Pin;
+use core::ptr::read_volatile;
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::task::{Context, Poll};
+
+use defmt::{warn, Format};
+use embassy_executor::raw::{task_from_waker, TaskPool, TaskStorage};
+use embassy_executor::Spawner;
+
+/// The maximum number of tasks reported.
+pub const MAX_TASKS: usize = 32;
+/// The size of an encoded [`TaskInfo`].
+pub const TASK_INFO_SIZE: usize = 24;
+
+const STATE_SPAWNED: u32 = 1 << 0;
+const STATE_RUN_QUEUED: u32 = 1 << 1;
+const STATE_TIMER_QUEUED: u32 = 1 << 2;
+const STATE_MASK: u32 = STATE_SPAWNED | STATE_RUN_QUEUED | STATE_TIMER_QUEUED;
+
+/// The header of a task spawned on each core's executor, zero if none.
+static REGISTERED: [AtomicU32; 2] = [AtomicU32::new(0), AtomicU32::new(0)];
+
+static REGISTRATIONS: TaskPool<Registration, 2> = TaskPool::new();
+
+/// The size of a task header alone, the future being zero sized.
+const TASK_HEADER_SIZE: usize = size_of::<TaskStorage<Pending<()>>>();
+
+extern "C" {
+    // The bounds of `.text`, `.data` and `.bss`, defined by cortex-m-rt.
+    static __stext: u32;
+    static __etext: u32;
+    static __sdata: u32;
+    static __ebss: u32;
+}
+
+fn bounds(start: &u32, end: &u32) -> Range<u32> {
+    (start as *const u32 as u32)..(end as *const u32 as u32)
+}
+
+fn read_word(address: u32) -> u32 {
+    // SAFETY: Callers only pass addresses within `.data` or `.bss`.
+    unsafe { read_volatile(address as *const u32) }
+}
+
+/// Register the executor of `spawner` for introspection, `core` being the core it runs on.
+pub(crate) fn register_executor(core: usize, spawner: Spawner) {
+    if spawner
+        .spawn(REGISTRATIONS.spawn(|| Registration { core }))
+        .is_err()
+    {
+        warn!("Failed to register the executor of core{}", core);
+    }
+}
+
+/// Records the header of the task polling it, which points at the task's executor.
+struct Registration {
+    core: usize,
+}
+
+impl Future for Registration {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // Panics unless polled by an embassy task, whose waker's data is its header.
+        task_from_waker(cx.waker());
+        REGISTERED[self.core].store(cx.waker().data() as u32, Ordering::Relaxed);
+        Poll::Ready(())
+    }
+}
+
+/// Byte offsets of the fields of `embassy_executor::raw::TaskHeader`.
+#[derive(Clone, Copy)]
+pub struct TaskHeaderLayout {
+    pub state: u32,
+    pub executor: u32,
+    pub poll_fn: u32,
+    /// `None` without the `integrated-timers` feature.
+    pub expires_at: Option<u32>,
+    pub size: u32,
+}
+
+impl TaskHeaderLayout {
+    /// embassy-executor 0.6.3 with `integrated-timers`, the 8 byte aligned expiry placed first.
+    pub const EMBASSY_EXECUTOR_0_6: Self = Self {
+        expires_at: Some(0),
+        state: 8,
+        executor: 16,
+        poll_fn: 20,
+        size: 32,
+    };
+
+    /// embassy-executor 0.6.3 without `integrated-timers`, the fields in declaration order.
+    pub const EMBASSY_EXECUTOR_0_6_WITHOUT_TIMERS: Self = Self {
+        expires_at: None,
+        state: 0,
+        executor: 8,
+        poll_fn: 12,
+        size: 16,
+    };
+}
+
+/// Whether `integrated-timers` is enabled is only known from the size of the header.
+const DEFAULT_LAYOUT: TaskHeaderLayout = match TASK_HEADER_SIZE {
+    32 => TaskHeaderLayout::EMBASSY_EXECUTOR_0_6,
+    16 => TaskHeaderLayout::EMBASSY_EXECUTOR_0_6_WITHOUT_TIMERS,
+    _ => panic!("The task header doesn't match embassy-executor 0.6.3"),
+};
+
+impl Default for TaskHeaderLayout {
+    fn default() -> Self {
+        DEFAULT_LAYOUT
+    }
+}
+
+#[derive(Clone, Copy, Default, Format)]
+pub struct TaskInfo {
+    /// The address of the task header (and its storage).
+    pub address: u32,
+    /// The poll function, whose symbol identifies the task's future.
+    pub poll_fn: u32,
+    /// The core whose executor the task was spawned on.
+    pub core: u8,
+    pub spawned: bool,
+    /// Woken and waiting to be polled.
+    pub run_queued: bool,
+    /// Waiting for a timer, see `expires_at`.
+    pub timer_queued: bool,
+    /// The expiry of the timer in ticks, if `timer_queued`.
+    pub expires_at: u64,
+}
+
+impl TaskInfo {
+    /// Encode the task in the (little endian) wire format:
+    ///
+    /// | bytes | field |
+    /// |-------|-------|
+    /// | 4 | header address |
+    /// | 4 | poll function |
+    /// | 1 | core |
+    /// | 1 | state: spawned (bit 0), run queued (1), timer queued (2) |
+    /// | 6 | reserved |
+    /// | 8 | timer expiry (ticks) |
+    pub fn encode(&self) -> [u8; TASK_INFO_SIZE] {
+        let mut data = [0; TASK_INFO_SIZE];
+        data[0..4].copy_from_slice(&self.address.to_le_bytes());
+        data[4..8].copy_from_slice(&self.poll_fn.to_le_bytes());
+        data[8] = self.core;
+        data[9] =
+            self.spawned as u8 | (self.run_queued as u8) << 1 | (self.timer_queued as u8) << 2;
+        data[16..24].copy_from_slice(&self.expires_at.to_le_bytes());
+        data
+    }
+}
+
+/// Find the tasks of the registered executors, returning the number written to `tasks`.
+/// Tasks which have run to completion (and haven't been respawned) are not reported.
+pub fn scan(layout: &TaskHeaderLayout, tasks: &mut [TaskInfo]) -> usize {
+    // SAFETY: Only the addresses of the linker symbols are used.
+    let (text, ram) = unsafe { (bounds(&__stext, &__etext), bounds(&__sdata, &__ebss)) };
+    // The registered headers are in `.bss`, and keep pointing at their executor once complete.
+    let executors = REGISTERED
+        .each_ref()
+        .map(|header| match header.load(Ordering::Relaxed) {
+            0 => 0,
+            header => read_word(header + layout.executor),
+        });
+
+    let mut count = 0;
+    let mut address = ram.start;
+    while address + layout.size <= ram.end && count < tasks.len() {
+        let executor = read_word(address + layout.executor);
+        let Some(core) = executors.iter().position(|e| *e != 0 && *e == executor) else {
+            address += 4;
+            continue;
+        };
+        let state = read_word(address + layout.state);
+        let poll_fn = read_word(address + layout.poll_fn);
+        if state & !STATE_MASK != 0
+            || state & STATE_SPAWNED == 0
+            || poll_fn & 1 == 0
+            || !text.contains(&(poll_fn & !1))
+        {
+            address += 4;
+            continue;
+        }
+
+        let timer_queued = state & STATE_TIMER_QUEUED != 0;
+        let expires_at = match layout.expires_at {
+            Some(offset) if timer_queued => {
+                let low = read_word(address + offset) as u64;
+                let high = read_word(address + offset + 4) as u64;
+                high << 32 | low
+            }
+            _ => 0,
+        };
+        tasks[count] = TaskInfo {
+            address,
+            poll_fn: poll_fn & !1,
+            core: core as u8,
+            spawned: true,
+            run_queued: state & STATE_RUN_QUEUED != 0,
+            timer_queued,
+            expires_at,
+        };
+        count += 1;
+        address += layout.size;
+    }
+    count
+}
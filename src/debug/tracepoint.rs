@@ -0,0 +1,371 @@
+//! Non-halting tracepoints.
+//!
+//! A tracepoint is a hardware breakpoint which is serviced by this core rather than a human:
+//! when the traced core hits it, the configured registers and memory words are captured and
+//! the core is immediately stepped over the breakpoint and resumed. The traced core is only
+//! stopped for the duration of the capture.
+//!
+//! A remote debug session disables the breakpoint unit when it releases the core, the
+//! tracepoints are then re-armed by [`Tracer::next`].
+use defmt::{debug, trace, unwrap, warn, Format};
+use embassy_futures::select::{select, Either};
+use embassy_net::{driver::Driver, tcp::TcpSocket};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::Write;
+
+use crate::debug::core_debugger::{CoreDebugger, Error};
+use crate::debug::dap::Core;
+use crate::debug::registers::{BpComp, BpCtrl, CoreRegister, Dfsr};
+
+/// The maximum number of registers captured per record.
+pub const MAX_REGISTERS: usize = 8;
+/// The maximum number of memory words captured per record.
+pub const MAX_MEMORY_WORDS: usize = 8;
+/// The size of an encoded [`TraceRecord`].
+pub const RECORD_SIZE: usize = 16 + 4 * (MAX_REGISTERS + MAX_MEMORY_WORDS);
+
+/// The interval between checks for a hit, doubled up to the maximum whilst none are.
+const MIN_POLL_INTERVAL: Duration = Duration::from_millis(1);
+const MAX_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// What to capture when a tracepoint is hit.
+#[derive(Clone, Copy, Default)]
+pub struct Capture {
+    registers: [Option<CoreRegister>; MAX_REGISTERS],
+    memory: [Option<u32>; MAX_MEMORY_WORDS],
+}
+
+impl Capture {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Capture a core register, panics if [`MAX_REGISTERS`] are already captured.
+    pub fn register(&mut self, register: CoreRegister) -> &mut Self {
+        let slot = self.registers.iter_mut().find(|r| r.is_none());
+        *unwrap!(slot, "Too many registers") = Some(register);
+        self
+    }
+
+    /// Capture the word at `address`, panics if [`MAX_MEMORY_WORDS`] are already captured.
+    pub fn memory(&mut self, address: u32) -> &mut Self {
+        let slot = self.memory.iter_mut().find(|a| a.is_none());
+        *unwrap!(slot, "Too many memory words") = Some(address);
+        self
+    }
+}
+
+/// The state captured when a tracepoint was hit.
+#[derive(Clone, Copy, Format)]
+pub struct TraceRecord {
+    pub tracepoint: u8,
+    /// Microseconds since boot.
+    pub timestamp: u64,
+    pub registers: [u32; MAX_REGISTERS],
+    pub register_count: u8,
+    pub memory: [u32; MAX_MEMORY_WORDS],
+    pub memory_count: u8,
+}
+
+impl TraceRecord {
+    /// Encode the record in the (little endian) wire format:
+    ///
+    /// | bytes | field |
+    /// |-------|-------|
+    /// | 1 | tracepoint index |
+    /// | 1 | register count |
+    /// | 1 | memory word count |
+    /// | 5 | reserved |
+    /// | 8 | timestamp (µs) |
+    /// | 4 * [`MAX_REGISTERS`] | registers, in capture order |
+    /// | 4 * [`MAX_MEMORY_WORDS`] | memory words, in capture order |
+    pub fn encode(&self) -> [u8; RECORD_SIZE] {
+        let mut data = [0; RECORD_SIZE];
+        data[0] = self.tracepoint;
+        data[1] = self.register_count;
+        data[2] = self.memory_count;
+        data[8..16].copy_from_slice(&self.timestamp.to_le_bytes());
+        let words = self.registers.iter().chain(self.memory.iter());
+        for (chunk, word) in data[16..].chunks_exact_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        data
+    }
+}
+
+/// A fixed capacity buffer of records, the oldest records are overwritten when full.
+pub struct TraceBuffer<const N: usize> {
+    records: [Option<TraceRecord>; N],
+    head: usize,
+    len: usize,
+    dropped: usize,
+}
+
+impl<const N: usize> Default for TraceBuffer<N> {
+    fn default() -> Self {
+        Self {
+            records: [None; N],
+            head: 0,
+            len: 0,
+            dropped: 0,
+        }
+    }
+}
+
+impl<const N: usize> TraceBuffer<N> {
+    pub fn push(&mut self, record: TraceRecord) {
+        let index = (self.head + self.len) % N;
+        self.records[index] = Some(record);
+        if self.len == N {
+            self.head = (self.head + 1) % N;
+            self.dropped += 1;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<TraceRecord> {
+        if self.len == 0 {
+            return None;
+        }
+        let record = self.records[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        record
+    }
+
+    /// The number of records overwritten before they were read.
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    /// The number of records overwritten before they were read, resetting it once reported.
+    pub fn take_dropped(&mut self) -> usize {
+        core::mem::take(&mut self.dropped)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Tracepoint {
+    address: u32,
+    capture: Capture,
+}
+
+pub struct Tracer<CORE: Core, const N: usize = 4> {
+    debugger: CoreDebugger<CORE>,
+    tracepoints: [Option<Tracepoint>; N],
+}
+
+impl<CORE: Core, const N: usize> Tracer<CORE, N> {
+    pub fn new(debugger: CoreDebugger<CORE>) -> Self {
+        Self {
+            debugger,
+            tracepoints: [None; N],
+        }
+    }
+
+    /// Arm a tracepoint on the instruction at `address`, returning its index.
+    pub async fn arm(&mut self, address: u32, capture: Capture) -> Result<u8, Error> {
+        let index = self
+            .tracepoints
+            .iter()
+            .position(|t| t.is_none())
+            .ok_or(Error::NoComparator)?;
+        self.debugger.enable_debug().await?;
+        self.debugger.set_breakpoint(address).await?;
+        self.tracepoints[index] = Some(Tracepoint { address, capture });
+        Ok(index as u8)
+    }
+
+    pub async fn disarm(&mut self, index: u8) -> Result<(), Error> {
+        let tracepoint = self
+            .tracepoints
+            .get_mut(index as usize)
+            .ok_or(Error::InvalidIndex)?;
+        if let Some(tracepoint) = tracepoint.take() {
+            self.debugger.clear_breakpoint(tracepoint.address).await?;
+        }
+        Ok(())
+    }
+
+    /// Wait for a tracepoint to be hit. The record is captured and the core resumed before
+    /// this returns, it is safe to cancel.
+    pub async fn next(&mut self) -> Result<TraceRecord, Error> {
+        let mut interval = MIN_POLL_INTERVAL;
+        loop {
+            let tracepoints = &self.tracepoints;
+            let (enabled, record) = self
+                .debugger
+                .with_attached(|debugger| {
+                    if !debugger.dhcsr()?.c_debugen() {
+                        return Ok((false, None));
+                    }
+                    Self::service(debugger, tracepoints).map(|record| (true, record))
+                })
+                .await?;
+            if let Some(record) = record {
+                return Ok(record);
+            }
+            if !enabled && self.tracepoints.iter().any(Option::is_some) {
+                self.rearm().await?;
+            }
+            Timer::after(interval).await;
+            interval = core::cmp::min(interval * 2, MAX_POLL_INTERVAL);
+        }
+    }
+
+    /// Re-arm the tracepoints after a remote debug session has released the core, disabling
+    /// debug and the breakpoint unit.
+    async fn rearm(&mut self) -> Result<(), Error> {
+        debug!("Re-arming tracepoints");
+        self.debugger.enable_debug().await?;
+        for tracepoint in self.tracepoints.iter().flatten() {
+            self.debugger.set_breakpoint(tracepoint.address).await?;
+        }
+        Ok(())
+    }
+
+    /// Capture and step over the tracepoint the core is halted on, if any. Halts caused by
+    /// anything else (e.g. a remote debugger) are left alone.
+    fn service(
+        debugger: &mut CoreDebugger<CORE>,
+        tracepoints: &[Option<Tracepoint>; N],
+    ) -> Result<Option<TraceRecord>, Error> {
+        if !debugger.dhcsr()?.s_halt() {
+            return Ok(None);
+        }
+        let dfsr = Dfsr(debugger.read_word(Dfsr::ADDRESS)?);
+        if !dfsr.bkpt() {
+            return Ok(None);
+        }
+        let pc = debugger.read_core_register_attached(CoreRegister::Pc)?;
+        let Some((index, tracepoint)) = tracepoints
+            .iter()
+            .enumerate()
+            .find_map(|(i, t)| t.filter(|t| t.address == pc).map(|t| (i, t)))
+        else {
+            return Ok(None);
+        };
+        let timestamp = Instant::now().as_micros();
+        debugger.write_word(Dfsr::ADDRESS, dfsr.clear_value())?;
+
+        let mut record = TraceRecord {
+            tracepoint: index as u8,
+            timestamp,
+            registers: [0; MAX_REGISTERS],
+            register_count: 0,
+            memory: [0; MAX_MEMORY_WORDS],
+            memory_count: 0,
+        };
+        for register in tracepoint.capture.registers.iter().flatten() {
+            let value = debugger.read_core_register_attached(*register)?;
+            record.registers[record.register_count as usize] = value;
+            record.register_count += 1;
+        }
+        for address in tracepoint.capture.memory.iter().flatten() {
+            let value = debugger.read_word(*address)?;
+            record.memory[record.memory_count as usize] = value;
+            record.memory_count += 1;
+        }
+
+        Self::step_over(debugger, pc)?;
+        trace!("Tracepoint {} hit at {:#x}", index, pc);
+        Ok(Some(record))
+    }
+
+    /// Disable the comparator matching `pc`, step, re-enable it and resume.
+    fn step_over(debugger: &mut CoreDebugger<CORE>, pc: u32) -> Result<(), Error> {
+        let ctrl = BpCtrl(debugger.read_word(BpCtrl::ADDRESS)?);
+        let mut comparator = None;
+        for index in 0..ctrl.num_code() {
+            let comp = BpComp(debugger.read_word(BpComp::address(index))?);
+            if comp.enable() && comp.comp() == pc {
+                comparator = Some((index, comp));
+                debugger.write_word(BpComp::address(index), 0)?;
+                break;
+            }
+        }
+
+        debugger.write_dhcsr(|dhcsr| {
+            dhcsr.set_c_step(true);
+            dhcsr.set_c_maskints(true);
+        })?;
+        debugger.poll(|debugger| Ok(debugger.dhcsr()?.s_halt()))?;
+
+        if let Some((index, comp)) = comparator {
+            debugger.write_word(BpComp::address(index), comp.0)?;
+        }
+        // Clear the halt caused by the step before resuming.
+        let dfsr = Dfsr(debugger.read_word(Dfsr::ADDRESS)?);
+        debugger.write_word(Dfsr::ADDRESS, dfsr.clear_value())?;
+        debugger.write_dhcsr(|_| {})
+    }
+
+    /// Stream records to a TCP client connected to `port`. Whilst no client is connected,
+    /// records are retained in a buffer of `BUFFER` records and sent once one connects.
+    pub async fn listen<const BUFFER: usize>(
+        mut self,
+        stack: &'static embassy_net::Stack<impl Driver>,
+        port: u16,
+    ) -> ! {
+        let mut rx_buffer = [0; 64];
+        let mut tx_buffer = [0; 4 * RECORD_SIZE];
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+        let mut buffer = TraceBuffer::<BUFFER>::default();
+
+        loop {
+            debug!("Waiting for trace connection");
+            {
+                let accept = socket.accept(port);
+                let mut accept = core::pin::pin!(accept);
+                loop {
+                    match select(&mut accept, self.next()).await {
+                        Either::First(Ok(())) => break,
+                        Either::First(Err(e)) => {
+                            warn!("Failed to accept trace connection: {:?}", e);
+                            break;
+                        }
+                        Either::Second(Ok(record)) => buffer.push(record),
+                        Either::Second(Err(e)) => {
+                            warn!("Tracepoint error: {:?}", e);
+                            Timer::after(MAX_POLL_INTERVAL).await;
+                        }
+                    }
+                }
+            }
+
+            if socket.state() == embassy_net::tcp::State::Established {
+                let dropped = buffer.take_dropped();
+                if dropped > 0 {
+                    warn!("{} trace records dropped", dropped);
+                }
+                while let Some(record) = buffer.pop() {
+                    if socket.write_all(&record.encode()).await.is_err() {
+                        break;
+                    }
+                }
+                loop {
+                    let record = match self.next().await {
+                        Ok(record) => record,
+                        Err(e) => {
+                            warn!("Tracepoint error: {:?}", e);
+                            // Back off rather than retrying a persistent error immediately.
+                            Timer::after(MAX_POLL_INTERVAL).await;
+                            continue;
+                        }
+                    };
+                    if let Err(e) = socket.write_all(&record.encode()).await {
+                        warn!("Write error: {:?}", e);
+                        buffer.push(record);
+                        break;
+                    }
+                }
+            }
+
+            socket.abort();
+            let _ = socket.flush().await;
+            debug!("Trace connection closed");
+        }
+    }
+}
@@ -0,0 +1,456 @@
+//! Builder and decoder for CMSIS-DAP `DAP_Transfer` commands.
+//!
+//! This allows debug register accesses to be issued through [`dap_rs::dap::Dap::process_command`]
+//! in the same way a remote host would, without hand-assembling the request bytes.
+use defmt::Format;
+
+use crate::debug::registers::Aircr;
+
+pub(crate) const TRANSFER_COMMAND_ID: u8 = 0x05;
+
+/// Transfer request byte fields (CMSIS-DAP `DAP_Transfer`).
+const APNDP: u8 = 1 << 0;
+const RNW: u8 = 1 << 1;
+const ADDRESS: u8 = 0b11 << 2;
+const MATCH_VALUE: u8 = 1 << 4;
+const MATCH_MASK: u8 = 1 << 5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum Port {
+    Dp,
+    Ap,
+}
+
+/// A DP or AP register, the address is the A[3:2] field of the SWD request.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) struct Register {
+    port: Port,
+    address: u8,
+}
+
+impl Register {
+    const fn new(port: Port, address: u8) -> Self {
+        Self { port, address }
+    }
+
+    /// The request byte for this register, excluding the match and timestamp flags.
+    const fn request(&self, read: bool) -> u8 {
+        let port = match self.port {
+            Port::Dp => 0,
+            Port::Ap => APNDP,
+        };
+        let read = if read { RNW } else { 0 };
+        port | read | (self.address & ADDRESS)
+    }
+}
+
+/// Debug Port registers (ADIv5 and later).
+pub(crate) mod dp {
+    use super::{Port, Register};
+
+    /// Read-only.
+    pub(crate) const DPIDR: Register = Register::new(Port::Dp, 0x0);
+    /// Write-only.
+    pub(crate) const ABORT: Register = Register::new(Port::Dp, 0x0);
+    pub(crate) const CTRL_STAT: Register = Register::new(Port::Dp, 0x4);
+    pub(crate) const SELECT: Register = Register::new(Port::Dp, 0x8);
+    pub(crate) const RDBUFF: Register = Register::new(Port::Dp, 0xc);
+
+    pub(crate) const ABORT_CLEAR_ALL: u32 = 0x1e;
+    pub(crate) const CTRL_STAT_CDBGPWRUPREQ: u32 = 1 << 28;
+    pub(crate) const CTRL_STAT_CDBGPWRUPACK: u32 = 1 << 29;
+    pub(crate) const CTRL_STAT_CSYSPWRUPREQ: u32 = 1 << 30;
+    pub(crate) const CTRL_STAT_CSYSPWRUPACK: u32 = 1 << 31;
+
+    /// Select the register bank `bank` of access port `ap`.
+    pub(crate) const fn select(ap: u8, bank: u8) -> u32 {
+        (ap as u32) << 24 | ((bank & 0xf) as u32) << 4
+    }
+}
+
+/// MEM-AP registers, all located in bank 0 except IDR.
+pub(crate) mod ap {
+    use super::{Port, Register};
+
+    pub(crate) const CSW: Register = Register::new(Port::Ap, 0x00);
+    pub(crate) const TAR: Register = Register::new(Port::Ap, 0x04);
+    pub(crate) const DRW: Register = Register::new(Port::Ap, 0x0c);
+    /// Located in bank 0xf.
+    pub(crate) const IDR: Register = Register::new(Port::Ap, 0x0c);
+
+    /// 32-bit accesses, no address increment, privileged debug access.
+    pub(crate) const CSW_WORD: u32 = 0xa200_0002;
+}
+
+/// Builds a `DAP_Transfer` request in the provided buffer.
+///
+/// The builder panics if the buffer can not hold the transfers, the buffer is expected to
+/// be a full DAP packet.
+pub(crate) struct Transfer<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+    reads: usize,
+}
+
+impl<'a> Transfer<'a> {
+    const HEADER_SIZE: usize = 3;
+
+    pub(crate) fn new(buffer: &'a mut [u8]) -> Self {
+        buffer[0] = TRANSFER_COMMAND_ID;
+        // DAP index, ignored for SWD.
+        buffer[1] = 0;
+        buffer[2] = 0;
+        Self {
+            buffer,
+            len: Self::HEADER_SIZE,
+            reads: 0,
+        }
+    }
+
+    fn push(&mut self, request: u8, value: Option<u32>) -> &mut Self {
+        let count = self.buffer[2];
+        assert!(count < u8::MAX, "Too many transfers");
+        self.buffer[self.len] = request;
+        self.len += 1;
+        if let Some(value) = value {
+            self.buffer[self.len..self.len + 4].copy_from_slice(&value.to_le_bytes());
+            self.len += 4;
+        }
+        self.buffer[2] = count + 1;
+        self
+    }
+
+    pub(crate) fn read(&mut self, register: Register) -> &mut Self {
+        self.reads += 1;
+        self.push(register.request(true), None)
+    }
+
+    pub(crate) fn write(&mut self, register: Register, value: u32) -> &mut Self {
+        self.push(register.request(false), Some(value))
+    }
+
+    /// Read a word of memory via the MEM-AP, the AP bank 0 must be selected.
+    pub(crate) fn read_memory(&mut self, address: u32) -> &mut Self {
+        self.write(ap::TAR, address).read(ap::DRW)
+    }
+
+    /// Write a word of memory via the MEM-AP, the AP bank 0 must be selected.
+    pub(crate) fn write_memory(&mut self, address: u32, value: u32) -> &mut Self {
+        self.write(ap::TAR, address).write(ap::DRW, value)
+    }
+
+    /// The number of transfers in the request.
+    pub(crate) fn count(&self) -> u8 {
+        self.buffer[2]
+    }
+
+    /// The number of words the response is expected to contain.
+    pub(crate) fn reads(&self) -> usize {
+        self.reads
+    }
+
+    pub(crate) fn finish(self) -> &'a [u8] {
+        &self.buffer[..self.len]
+    }
+}
+
+/// Whether a `DAP_Transfer` request (e.g. from a remote host) resets the target, by writing
+/// AIRCR with SYSRESETREQ via the MEM-AP.
+pub(crate) fn requests_reset(request: &[u8]) -> bool {
+    let [TRANSFER_COMMAND_ID, _, count, transfers @ ..] = request else {
+        return false;
+    };
+    let mut transfers = transfers;
+    let mut tar = None;
+    for _ in 0..*count {
+        let [request, rest @ ..] = transfers else {
+            return false;
+        };
+        transfers = rest;
+        let value = if request & RNW == 0 || request & MATCH_VALUE != 0 {
+            let [a, b, c, d, rest @ ..] = transfers else {
+                return false;
+            };
+            transfers = rest;
+            Some(u32::from_le_bytes([*a, *b, *c, *d]))
+        } else {
+            None
+        };
+        if request & MATCH_MASK != 0 {
+            continue;
+        }
+        let request = request & (APNDP | RNW | ADDRESS);
+        if request == ap::TAR.request(false) {
+            tar = value;
+        } else if request == ap::DRW.request(false)
+            && tar == Some(Aircr::ADDRESS)
+            && value.is_some_and(Aircr::requests_reset)
+        {
+            return true;
+        }
+    }
+    false
+}
+
+/// The ACK returned by the target for the last transfer.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum Ack {
+    Ok,
+    Wait,
+    Fault,
+    NoAck,
+    Invalid(u8),
+}
+
+impl From<u8> for Ack {
+    fn from(value: u8) -> Self {
+        match value & 0b111 {
+            0b001 => Self::Ok,
+            0b010 => Self::Wait,
+            0b100 => Self::Fault,
+            0b111 => Self::NoAck,
+            other => Self::Invalid(other),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum TransferError {
+    /// The response was not a `DAP_Transfer` response or was truncated.
+    Malformed,
+    /// The transfer at index `completed` was not acknowledged.
+    Ack { completed: u8, ack: Ack },
+    /// The target responded with a parity or protocol error.
+    Protocol { completed: u8 },
+    /// A value match comparison failed.
+    Mismatch { completed: u8 },
+}
+
+/// A successfully decoded `DAP_Transfer` response.
+pub(crate) struct TransferResponse<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> TransferResponse<'a> {
+    const ACK_MASK: u8 = 0b111;
+    const PROTOCOL_ERROR: u8 = 1 << 3;
+    const VALUE_MISMATCH: u8 = 1 << 4;
+
+    /// Decode the response to a request of `expected` transfers.
+    pub(crate) fn parse(response: &'a [u8], expected: u8) -> Result<Self, TransferError> {
+        let [command, completed, status, data @ ..] = response else {
+            return Err(TransferError::Malformed);
+        };
+        if *command != TRANSFER_COMMAND_ID {
+            return Err(TransferError::Malformed);
+        }
+        let completed = *completed;
+        if status & Self::PROTOCOL_ERROR != 0 {
+            return Err(TransferError::Protocol { completed });
+        }
+        if status & Self::VALUE_MISMATCH != 0 {
+            return Err(TransferError::Mismatch { completed });
+        }
+        let ack = Ack::from(status & Self::ACK_MASK);
+        if ack != Ack::Ok || completed != expected {
+            return Err(TransferError::Ack { completed, ack });
+        }
+        if data.len() % 4 != 0 {
+            return Err(TransferError::Malformed);
+        }
+        Ok(Self { data })
+    }
+
+    /// The words returned by the read transfers, in request order.
+    pub(crate) fn values(&self) -> impl Iterator<Item = u32> + 'a {
+        self.data
+            .chunks_exact(4)
+            .map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+    }
+
+    pub(crate) fn value(&self, index: usize) -> Option<u32> {
+        self.values().nth(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_request() {
+        let mut buffer = [0; 64];
+        let transfer = Transfer::new(&mut buffer);
+        assert_eq!(transfer.count(), 0);
+        assert_eq!(transfer.finish(), &[TRANSFER_COMMAND_ID, 0, 0]);
+    }
+
+    #[test]
+    fn register_requests() {
+        // APnDP in bit 0, RnW in bit 1 and A[3:2] in bits 2-3.
+        assert_eq!(dp::DPIDR.request(true), 0b0010);
+        assert_eq!(dp::ABORT.request(false), 0b0000);
+        assert_eq!(dp::CTRL_STAT.request(true), 0b0110);
+        assert_eq!(dp::SELECT.request(false), 0b1000);
+        assert_eq!(dp::RDBUFF.request(true), 0b1110);
+        assert_eq!(ap::CSW.request(false), 0b0001);
+        assert_eq!(ap::TAR.request(false), 0b0101);
+        assert_eq!(ap::DRW.request(true), 0b1111);
+    }
+
+    #[test]
+    fn select() {
+        assert_eq!(dp::select(0, 0), 0);
+        assert_eq!(dp::select(1, 0xf), 0x0100_00f0);
+    }
+
+    #[test]
+    fn reads_and_writes() {
+        let mut buffer = [0; 64];
+        let mut transfer = Transfer::new(&mut buffer);
+        transfer
+            .write(dp::SELECT, 0x0000_00f0)
+            .read(ap::IDR)
+            .read(dp::RDBUFF);
+        assert_eq!(transfer.count(), 3);
+        assert_eq!(transfer.reads(), 2);
+        assert_eq!(
+            transfer.finish(),
+            &[TRANSFER_COMMAND_ID, 0, 3, 0x08, 0xf0, 0, 0, 0, 0x0f, 0x0e]
+        );
+    }
+
+    #[test]
+    fn memory_accesses() {
+        let mut buffer = [0; 64];
+        let mut transfer = Transfer::new(&mut buffer);
+        transfer
+            .read_memory(0xe000_edf0)
+            .write_memory(0x2000_0000, 0x1234_5678);
+        assert_eq!(transfer.count(), 4);
+        assert_eq!(transfer.reads(), 1);
+        let mut expected = vec![TRANSFER_COMMAND_ID, 0, 4];
+        expected.push(0x05);
+        expected.extend(0xe000_edf0u32.to_le_bytes());
+        expected.push(0x0f);
+        expected.push(0x05);
+        expected.extend(0x2000_0000u32.to_le_bytes());
+        expected.push(0x0d);
+        expected.extend(0x1234_5678u32.to_le_bytes());
+        assert_eq!(transfer.finish(), expected);
+    }
+
+    #[test]
+    fn reset_requests() {
+        let mut buffer = [0; 64];
+        let mut transfer = Transfer::new(&mut buffer);
+        transfer
+            .read_memory(0xe000_edf0)
+            .write_memory(Aircr::ADDRESS, 0x05fa_0004);
+        assert!(requests_reset(transfer.finish()));
+
+        let mut buffer = [0; 64];
+        let mut transfer = Transfer::new(&mut buffer);
+        transfer
+            .write_memory(Aircr::ADDRESS, 0x05fa_0002)
+            .read_memory(Aircr::ADDRESS)
+            .write_memory(0x2000_0000, 0x05fa_0004);
+        assert!(!requests_reset(transfer.finish()));
+
+        // Truncated requests are ignored.
+        let mut buffer = [0; 64];
+        let mut transfer = Transfer::new(&mut buffer);
+        transfer.write_memory(Aircr::ADDRESS, 0x05fa_0004);
+        let request = transfer.finish();
+        assert!(!requests_reset(&request[..request.len() - 1]));
+        assert!(!requests_reset(&[0x06, 0, 1]));
+    }
+
+    #[test]
+    fn acks() {
+        assert!(Ack::from(0b001) == Ack::Ok);
+        assert!(Ack::from(0b010) == Ack::Wait);
+        assert!(Ack::from(0b100) == Ack::Fault);
+        assert!(Ack::from(0b111) == Ack::NoAck);
+        assert!(Ack::from(0b011) == Ack::Invalid(0b011));
+    }
+
+    #[test]
+    fn parse_ok() {
+        let mut response = vec![TRANSFER_COMMAND_ID, 2, 0b001];
+        response.extend(0x1234_5678u32.to_le_bytes());
+        response.extend(1u32.to_le_bytes());
+        let Ok(response) = TransferResponse::parse(&response, 2) else {
+            panic!("failed to parse");
+        };
+        assert_eq!(response.value(0), Some(0x1234_5678));
+        assert_eq!(response.value(1), Some(1));
+        assert_eq!(response.value(2), None);
+    }
+
+    #[test]
+    fn parse_wait() {
+        let response = [TRANSFER_COMMAND_ID, 0, 0b010];
+        assert!(matches!(
+            TransferResponse::parse(&response, 1),
+            Err(TransferError::Ack {
+                completed: 0,
+                ack: Ack::Wait
+            })
+        ));
+    }
+
+    #[test]
+    fn parse_fault() {
+        let response = [TRANSFER_COMMAND_ID, 1, 0b100];
+        assert!(matches!(
+            TransferResponse::parse(&response, 2),
+            Err(TransferError::Ack {
+                completed: 1,
+                ack: Ack::Fault
+            })
+        ));
+    }
+
+    #[test]
+    fn parse_incomplete() {
+        // Acknowledged, but fewer transfers than requested were executed.
+        let response = [TRANSFER_COMMAND_ID, 1, 0b001];
+        assert!(matches!(
+            TransferResponse::parse(&response, 2),
+            Err(TransferError::Ack {
+                completed: 1,
+                ack: Ack::Ok
+            })
+        ));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let protocol = [TRANSFER_COMMAND_ID, 0, 0b1001];
+        assert!(matches!(
+            TransferResponse::parse(&protocol, 1),
+            Err(TransferError::Protocol { completed: 0 })
+        ));
+        let mismatch = [TRANSFER_COMMAND_ID, 0, 0b1_0001];
+        assert!(matches!(
+            TransferResponse::parse(&mismatch, 1),
+            Err(TransferError::Mismatch { completed: 0 })
+        ));
+    }
+
+    #[test]
+    fn parse_malformed() {
+        for response in [
+            &[][..],
+            &[TRANSFER_COMMAND_ID, 1][..],
+            &[0x06, 1, 0b001][..],
+            &[TRANSFER_COMMAND_ID, 1, 0b001, 0x78, 0x56][..],
+        ] {
+            assert!(matches!(
+                TransferResponse::parse(response, 1),
+                Err(TransferError::Malformed)
+            ));
+        }
+    }
+}
@@ -0,0 +1,208 @@
+//! Live memory watching without halting the application core.
+//!
+//! Both cores share the bus, so the watched variables are read directly from this core while
+//! the application keeps running. A client configures the watch by sending (little endian):
+//!
+//! | bytes | field |
+//! |-------|-------|
+//! | 4 | sample interval (µs), at least [`MIN_INTERVAL_US`] |
+//! | 1 | entry count, at most [`MAX_ENTRIES`] |
+//! | 3 | reserved |
+//! | 8 * count | entries: address (4), size in bytes (4) |
+//!
+//! Each sample is then sent as an 8 byte timestamp (µs since boot) followed by the contents of
+//! every entry, in order. Sending another configuration replaces the current one. Entries must
+//! lie within SRAM or the memory mapped flash, reads elsewhere could have side effects.
+use core::ops::Range;
+use core::ptr::read_volatile;
+
+use defmt::{debug, warn, Format};
+use embassy_futures::select::{select, Either};
+use embassy_net::{driver::Driver, tcp::TcpSocket};
+use embassy_time::{Duration, Instant, Ticker};
+use embedded_io_async::Write;
+
+/// The maximum number of watched ranges.
+pub const MAX_ENTRIES: usize = 16;
+/// The maximum total number of bytes sampled.
+pub const MAX_SAMPLE_SIZE: usize = 256;
+pub const MIN_INTERVAL_US: u32 = 1000;
+
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 8;
+const CONFIG_SIZE: usize = HEADER_SIZE + ENTRY_SIZE * MAX_ENTRIES;
+
+/// The regions which can be read without side effects.
+const READABLE: [Range<u32>; 2] = [
+    0x1000_0000..0x1100_0000, // XIP
+    0x2000_0000..0x2004_2000, // SRAM
+];
+
+#[derive(Clone, Copy, Default)]
+struct Entry {
+    address: u32,
+    size: u32,
+}
+
+impl Entry {
+    /// Read the entry, using a single access for aligned words and half words.
+    fn sample(&self, data: &mut [u8]) {
+        // SAFETY: The entry has been validated to be within a readable region.
+        unsafe {
+            match self.size {
+                4 if self.address % 4 == 0 => {
+                    let value = read_volatile(self.address as *const u32);
+                    data.copy_from_slice(&value.to_le_bytes());
+                }
+                2 if self.address % 2 == 0 => {
+                    let value = read_volatile(self.address as *const u16);
+                    data.copy_from_slice(&value.to_le_bytes());
+                }
+                _ => {
+                    for (i, byte) in data.iter_mut().enumerate() {
+                        *byte = read_volatile((self.address as usize + i) as *const u8);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Format)]
+pub enum ConfigError {
+    TooManyEntries,
+    IntervalTooShort,
+    SampleTooLarge,
+    Unreadable { address: u32, size: u32 },
+}
+
+struct Config {
+    interval: Duration,
+    entries: [Entry; MAX_ENTRIES],
+    count: usize,
+}
+
+impl Config {
+    /// The size of the configuration starting with `header`.
+    fn size(header: &[u8]) -> Result<usize, ConfigError> {
+        let count = header[4] as usize;
+        if count > MAX_ENTRIES {
+            return Err(ConfigError::TooManyEntries);
+        }
+        Ok(HEADER_SIZE + ENTRY_SIZE * count)
+    }
+
+    fn parse(data: &[u8]) -> Result<Self, ConfigError> {
+        let word = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+        let interval = word(0);
+        if interval < MIN_INTERVAL_US {
+            return Err(ConfigError::IntervalTooShort);
+        }
+        let count = (Self::size(data)? - HEADER_SIZE) / ENTRY_SIZE;
+        let mut entries = [Entry::default(); MAX_ENTRIES];
+        let mut total = 0;
+        for (i, entry) in entries[..count].iter_mut().enumerate() {
+            let offset = HEADER_SIZE + ENTRY_SIZE * i;
+            let (address, size) = (word(offset), word(offset + 4));
+            let readable = address.checked_add(size).is_some_and(|end| {
+                READABLE
+                    .iter()
+                    .any(|region| region.contains(&address) && end <= region.end)
+            });
+            if !readable {
+                return Err(ConfigError::Unreadable { address, size });
+            }
+            total += size as usize;
+            if total > MAX_SAMPLE_SIZE {
+                return Err(ConfigError::SampleTooLarge);
+            }
+            *entry = Entry { address, size };
+        }
+        Ok(Self {
+            interval: Duration::from_micros(interval as u64),
+            entries,
+            count,
+        })
+    }
+
+    /// Sample every entry, returning the encoded sample.
+    fn sample<'a>(&self, buffer: &'a mut [u8; 8 + MAX_SAMPLE_SIZE]) -> &'a [u8] {
+        buffer[..8].copy_from_slice(&Instant::now().as_micros().to_le_bytes());
+        let mut len = 8;
+        for entry in &self.entries[..self.count] {
+            entry.sample(&mut buffer[len..len + entry.size as usize]);
+            len += entry.size as usize;
+        }
+        &buffer[..len]
+    }
+}
+
+/// Stream samples of the memory configured by a TCP client connected to `port`.
+pub async fn listen(stack: &'static embassy_net::Stack<impl Driver>, port: u16) -> ! {
+    let mut rx_buffer = [0; CONFIG_SIZE];
+    let mut tx_buffer = [0; 4 * (8 + MAX_SAMPLE_SIZE)];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(Duration::from_secs(10)));
+
+    loop {
+        debug!("Waiting for memory watch connection");
+        if let Err(e) = socket.accept(port).await {
+            warn!("Failed to accept memory watch connection: {:?}", e);
+            continue;
+        }
+
+        let mut config: Option<Config> = None;
+        let mut ticker = Ticker::every(Duration::from_secs(1));
+        let mut received = [0; CONFIG_SIZE];
+        let mut received_len = 0;
+        let mut sample = [0; 8 + MAX_SAMPLE_SIZE];
+        loop {
+            match select(socket.read(&mut received[received_len..]), ticker.next()).await {
+                Either::First(Ok(0)) => break,
+                Either::First(Ok(n)) => {
+                    received_len += n;
+                    if received_len < HEADER_SIZE {
+                        continue;
+                    }
+                    let result = Config::size(&received).and_then(|size| {
+                        if received_len < size {
+                            return Ok(None);
+                        }
+                        Config::parse(&received[..size]).map(|config| Some((config, size)))
+                    });
+                    match result {
+                        Ok(None) => {}
+                        Ok(Some((new, size))) => {
+                            debug!("Watching {} ranges every {}", new.count, new.interval);
+                            ticker = Ticker::every(new.interval);
+                            config = Some(new);
+                            received.copy_within(size..received_len, 0);
+                            received_len -= size;
+                        }
+                        Err(e) => {
+                            warn!("Invalid memory watch configuration: {:?}", e);
+                            break;
+                        }
+                    }
+                }
+                Either::First(Err(e)) => {
+                    warn!("Read error: {:?}", e);
+                    break;
+                }
+                Either::Second(()) => {
+                    let Some(config) = config.as_ref() else {
+                        continue;
+                    };
+                    if let Err(e) = socket.write_all(config.sample(&mut sample)).await {
+                        warn!("Write error: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        }
+
+        socket.abort();
+        let _ = socket.flush().await;
+        debug!("Memory watch connection closed");
+    }
+}
@@ -1,53 +1,91 @@
 use core::{
     cell::RefCell,
-    ops::Deref,
-    sync::atomic::{AtomicBool, Ordering},
+    ops::Range,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
 };
 
-use defmt::{trace, unwrap, warn, Format};
-use embassy_boot_rp::{AlignedBuffer, FirmwareUpdaterConfig};
-use embassy_embedded_hal::flash::partition::BlockingPartition;
+use defmt::{info, trace, unwrap, warn, Format};
 use embassy_rp::{
-    flash::{Async, Flash, WRITE_SIZE},
+    flash::{Async, Flash, ERASE_SIZE},
     peripherals::FLASH,
 };
-use embassy_sync::blocking_mutex::{
-    raw::{CriticalSectionRawMutex, NoopRawMutex},
-    NoopMutex,
-};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, NoopMutex};
 use embassy_sync::mutex::Mutex;
+#[cfg(not(feature = "direct-flash"))]
+use {
+    core::ops::Deref,
+    embassy_boot_rp::{AlignedBuffer, FirmwareUpdaterConfig},
+    embassy_embedded_hal::flash::partition::BlockingPartition,
+    embassy_rp::flash::WRITE_SIZE,
+    embassy_sync::blocking_mutex::raw::NoopRawMutex,
+    embedded_storage::nor_flash::ReadNorFlash,
+};
+
+#[cfg(not(feature = "direct-flash"))]
+use super::deferred::DeferredErases;
+
+#[cfg(feature = "direct-flash")]
+use super::direct;
+use super::layout::{
+    protected_base_address, table_base_address, ENTRY_POINT_COUNT, INTEGRITY_SIZE, LOAD_ADDRESS,
+    MAX_RESERVED_SIZE, MIN_RESERVED_SIZE, PAGE_SIZE, PROBE_RS_HEADER_SIZE, PROTECTED_SIZE,
+    RESERVED_SIZE, SCRATCH_A_ADDRESS, SCRATCH_B_ADDRESS, STACK_SIZE, TABLE_SIZE,
+};
+use super::progress;
+use super::protection;
+use super::routing::{self, RouteTarget};
+#[cfg(feature = "signed-updates")]
+use super::signature;
 
-/// A flag to indicate the flash algorithm has been initialised.
+/// The error returned for addresses routed to [`RouteTarget::Reject`].
+const REJECTED: usize = 2;
+
+/// A flag to indicate the flash algorithm has been initialised, cleared once the debug
+/// connection which ran it closes.
 pub(crate) static INIT_CALLED: AtomicBool = AtomicBool::new(false);
 
-/// The pertinent parts of the probe-rs config are included below,
-/// the associated code statically asserts the derivation of the
-/// various magic numbers contained within.
+/// The pertinent parts of the probe-rs config are included below (for the
+/// default `OTA_ALGORITHM` region, `target_yaml` generates them for others),
+/// the associated code statically asserts the derivation of the various magic
+/// numbers contained within.
 ///
 /// ```yaml
-///  instructions: +kwF4PpMA+D6TAHg+kz/5wC1oEcAvQ==
+///  instructions: +EwJ4PhMB+D4TAXg+EwD4PhMAeD4TP/nALWgRwC9
 ///  load_address: 0x20000004
+///  data_load_address: 0x20040000
 ///  stack_size: 512
 ///  pc_init: 0x1
 ///  pc_uninit: 0x5
 ///  pc_program_page: 0x9
 ///  pc_erase_sector: 0xd
+///  pc_verify: 0x11
+///  pc_erase_all: 0x15
 /// ```
 ///
-/// `instructions` is a base64 encoding of the following ARM assembly -
+/// `instructions` is a base64 encoding of the following ARM assembly, an
+/// `ldr`/`b` pair for each of the `ENTRY_POINT_COUNT` entry points followed by
+/// the shared call sequence -
 /// ```asm
-/// ldr r4, [pc, #0x3e8]
-/// b #0x10
-/// ldr r4, [pc, #0x3e8]
-/// b #0x10
-/// ldr r4, [pc, #0x3e8]
-/// b #0x10
-/// ldr r4, [pc, #0x3e8]
-/// b #0x10
+/// ldr r4, [pc, #0x3e0]
+/// b #0x18
+/// ldr r4, [pc, #0x3e0]
+/// b #0x18
+/// ldr r4, [pc, #0x3e0]
+/// b #0x18
+/// ldr r4, [pc, #0x3e0]
+/// b #0x18
+/// ldr r4, [pc, #0x3e0]
+/// b #0x18
+/// ldr r4, [pc, #0x3e0]
+/// b #0x18
 /// push {lr}
 /// blx r4
 /// pop {pc}
 /// ```
+///
+/// `data_load_address` places the page buffers in `SCRATCH_A` and `SCRATCH_B`,
+/// allowing probe-rs to load the next page into one whilst the previous page
+/// (in the other) is programmed.
 const _: () = {
     type ProbeRsArmHeader = [u32; 1];
     // For reasons*, probe-rs prefixes the instructions with a header but loads
@@ -61,18 +99,24 @@ const _: () = {
     // probe-rs, signalling that the flash algorithm has completed.
     let _probe_rs_arm_header: ProbeRsArmHeader = [0xBE00BE00];
     let probe_rs_arm_header_size = core::mem::size_of::<ProbeRsArmHeader>();
-    let load_address = RESERVED_BASE_ADDRESS + probe_rs_arm_header_size;
-    assert!(load_address == 0x20000004);
+    assert!(probe_rs_arm_header_size == PROBE_RS_HEADER_SIZE);
+    assert!(LOAD_ADDRESS == 0x20000004);
     // Probe-rs will place the stack immediately after the instructions. To keep
     // things simple, we choose the end of the reserved space to store the
     // function table.
-    let stack_size = 512;
+    let stack_size = STACK_SIZE;
     let thumb_instruction_size = 2;
-    let instruction_count = 11;
+    let instruction_count = 2 * ENTRY_POINT_COUNT + 3;
     let instructions_size = thumb_instruction_size * instruction_count;
     assert!(
         probe_rs_arm_header_size + instructions_size + stack_size + TABLE_SIZE <= RESERVED_SIZE
     );
+    // The region may be resized in memory.x, within the limits asserted there.
+    assert!(MIN_RESERVED_SIZE == 580 && MAX_RESERVED_SIZE == 1052);
+    // The flash pointer and checksum precede the table, all of which is made
+    // read-only by the MPU.
+    assert!(PROTECTED_SIZE == INTEGRITY_SIZE + TABLE_SIZE);
+    assert!(MIN_RESERVED_SIZE <= RESERVED_SIZE && RESERVED_SIZE <= MAX_RESERVED_SIZE);
     // Additionally, the layout of the entry points is such that the relative
     // offset to their respective function table entry is the same for all entry
     // points (i.e. each entry point is 4 bytes apart). The kicker is that these
@@ -80,18 +124,24 @@ const _: () = {
     // current instruction plus 4 bytes."
     let pc_relative_offset = 4;
     let lookup_delta = RESERVED_SIZE - probe_rs_arm_header_size - TABLE_SIZE - pc_relative_offset;
-    assert!(lookup_delta == 0x3e8);
+    assert!(lookup_delta == 0x3e0);
+    // Each branch targets the shared call sequence following the entry points.
+    let entry_point_size = 2 * thumb_instruction_size;
+    let call_sequence_offset = ENTRY_POINT_COUNT * entry_point_size;
+    assert!(call_sequence_offset == 0x18);
+    // Finally, the entry points are 4 bytes apart in the order of the function
+    // table, so the last (pc_erase_all) is at offset 0x14 (0x15 in thumb-mode).
+    assert!((ENTRY_POINT_COUNT - 1) * entry_point_size == 0x14);
+    // The table holds a function pointer per entry point.
+    type FunctionTable = [extern "C" fn(usize, usize, usize) -> usize; ENTRY_POINT_COUNT];
+    assert!(TABLE_SIZE == size_of::<FunctionTable>());
+    // probe-rs places its two page buffers consecutively from the data load
+    // address, so each scratch region must hold exactly a page.
+    assert!(PAGE_SIZE == ERASE_SIZE);
+    assert!(SCRATCH_A_ADDRESS == 0x20040000);
+    assert!(SCRATCH_B_ADDRESS == SCRATCH_A_ADDRESS + PAGE_SIZE);
 };
 
-/// The base address of the RAM region reserved for the flash algorithm.
-const RESERVED_BASE_ADDRESS: usize = 0x20000000;
-/// The size of the RAM region reserved for the flash algorithm.
-const RESERVED_SIZE: usize = 1024;
-/// The size of the function table used to store the pointers.
-const TABLE_SIZE: usize = size_of::<[extern "C" fn(usize, usize, usize) -> usize; 4]>();
-/// The location of the function table in the reserved RAM region.
-const TABLE_BASE_ADDRESS: usize = RESERVED_BASE_ADDRESS + RESERVED_SIZE - TABLE_SIZE;
-
 #[derive(Format)]
 pub enum Operation {
     Erase,
@@ -111,9 +161,114 @@ impl core::convert::TryFrom<usize> for Operation {
     }
 }
 
-/// Used to share the flash instance with the flash algorithm.
-/// Can't be correctly typed because Flash is generic over the flash size.
-static mut FLASH_POINTER: usize = 0;
+/// The maximum number of DFU sectors tracked, sectors beyond this are always
+/// erased immediately by `erase_sector` and by `program_page`, see
+/// [`DeferredErases`]. With `direct-flash`, the maximum number of image
+/// sectors which can be staged.
+pub(super) const MAX_TRACKED_SECTORS: usize = 256;
+
+/// A bitmap of DFU (or image) sectors.
+pub(super) struct SectorSet([AtomicU32; MAX_TRACKED_SECTORS / 32]);
+
+impl SectorSet {
+    pub(super) const fn new() -> Self {
+        Self([const { AtomicU32::new(0) }; MAX_TRACKED_SECTORS / 32])
+    }
+
+    pub(super) fn clear(&self) {
+        self.0
+            .iter()
+            .for_each(|word| word.store(0, Ordering::Relaxed));
+    }
+
+    /// Whether the sectors spanning `offsets` can be tracked.
+    pub(super) fn tracks(offsets: &Range<usize>) -> bool {
+        offsets.end.div_ceil(ERASE_SIZE) <= MAX_TRACKED_SECTORS
+    }
+
+    /// Marks the sectors spanning `offsets` as members (or not).
+    pub(super) fn mark(&self, offsets: Range<usize>, member: bool) {
+        for sector in offsets.start / ERASE_SIZE..offsets.end.div_ceil(ERASE_SIZE) {
+            let Some(word) = self.0.get(sector / 32) else {
+                break;
+            };
+            let bit = 1 << (sector % 32);
+            let value = word.load(Ordering::Relaxed);
+            let value = if member { value | bit } else { value & !bit };
+            word.store(value, Ordering::Relaxed);
+        }
+    }
+
+    /// Whether all the sectors spanning `offsets` are members.
+    pub(super) fn contains(&self, offsets: Range<usize>) -> bool {
+        (offsets.start / ERASE_SIZE..offsets.end.div_ceil(ERASE_SIZE)).all(|sector| {
+            self.0
+                .get(sector / 32)
+                .is_some_and(|word| word.load(Ordering::Relaxed) & (1 << (sector % 32)) != 0)
+        })
+    }
+
+    /// The offsets of the member sectors.
+    pub(super) fn sectors(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..MAX_TRACKED_SECTORS)
+            .filter(|sector| self.contains(sector * ERASE_SIZE..(sector + 1) * ERASE_SIZE))
+            .map(|sector| sector * ERASE_SIZE)
+    }
+
+    /// The bitmap, a bit per sector.
+    #[cfg(feature = "direct-flash")]
+    pub(super) fn words(&self) -> [u32; MAX_TRACKED_SECTORS / 32] {
+        core::array::from_fn(|i| self.0[i].load(Ordering::Relaxed))
+    }
+}
+
+/// The DFU sectors erased, or whose erase is deferred to `program_page` (or
+/// to `uninit` if the sector isn't programmed).
+#[cfg(not(feature = "direct-flash"))]
+static DFU_ERASES: DeferredErases = DeferredErases::new();
+
+extern "C" {
+    // The bounds of the `OTA_ALGORITHM` region, defined in memory.x.
+    static __ota_algorithm_start: u32;
+    static __ota_algorithm_end: u32;
+}
+
+/// The RAM region reserved for the flash algorithm.
+pub(crate) fn reserved_region() -> Range<usize> {
+    // SAFETY: Only the addresses of the linker symbols are used.
+    unsafe {
+        (&__ota_algorithm_start as *const u32 as usize)
+            ..(&__ota_algorithm_end as *const u32 as usize)
+    }
+}
+
+/// The location of the pointer used to share the flash instance with the
+/// flash algorithm, followed by the checksum and function table. It can't be
+/// correctly typed because Flash is generic over the flash size.
+fn flash_pointer_address() -> usize {
+    let region = reserved_region();
+    protected_base_address(region.start, region.len())
+}
+
+/// FNV-1a over the flash pointer and function table, which is stored between
+/// them.
+fn checksum() -> u32 {
+    let pointer = flash_pointer_address();
+    let table = (0..ENTRY_POINT_COUNT).map(|i| pointer + INTEGRITY_SIZE + 4 * i);
+    core::iter::once(pointer)
+        .chain(table)
+        // SAFETY: The addresses are within the reserved region.
+        .map(|address| unsafe { core::ptr::read_volatile(address as *const u32) })
+        .flat_map(u32::to_le_bytes)
+        .fold(0x811c_9dc5, |hash: u32, byte| {
+            (hash ^ byte as u32).wrapping_mul(0x0100_0193)
+        })
+}
+
+fn stored_checksum() -> u32 {
+    // SAFETY: The address is within the reserved region.
+    unsafe { core::ptr::read_volatile((flash_pointer_address() + 4) as *const u32) }
+}
 
 /// Flash algorithm methods using a const generic for reuse across flash sizes.
 /// These methods must be statically invoked (via the function table), therefore
@@ -129,23 +284,35 @@ impl<const FLASH_SIZE: usize> FlashAlgorithm<FLASH_SIZE> {
             NoopMutex<RefCell<Flash<'static, FLASH, Async, FLASH_SIZE>>>,
         >,
     ) {
-        let function_table: [extern "C" fn(usize, usize, usize) -> usize; 4] = [
+        let function_table: [extern "C" fn(usize, usize, usize) -> usize; ENTRY_POINT_COUNT] = [
             Self::init,
             Self::uninit,
             Self::program_page,
             Self::erase_sector,
+            Self::verify,
+            Self::erase_all,
         ];
         debug_assert_eq!(core::mem::size_of_val(&function_table), TABLE_SIZE);
+        let region = reserved_region();
+        let table_base_address = table_base_address(region.start, region.len());
+        let flash_pointer_address = flash_pointer_address();
         // SAFETY: These memory locations are reserved for the flash algorithm.
         // SAFETY: All values are pointers to static items.
         unsafe {
             core::ptr::copy_nonoverlapping(
                 function_table.as_ptr(),
-                TABLE_BASE_ADDRESS as *mut _,
+                table_base_address as *mut _,
                 function_table.len(),
             );
-            FLASH_POINTER = flash as *const _ as usize;
+            core::ptr::write_volatile(
+                flash_pointer_address as *mut usize,
+                flash as *const _ as usize,
+            );
+            core::ptr::write_volatile((flash_pointer_address + 4) as *mut u32, checksum());
         }
+        protection::protect();
+        #[cfg(feature = "direct-flash")]
+        direct::set_staging_offset(FLASH_SIZE / 2);
     }
 
     fn flash() -> &'static Mutex<
@@ -155,12 +322,13 @@ impl<const FLASH_SIZE: usize> FlashAlgorithm<FLASH_SIZE> {
         // SAFETY: Must be invoked after the flash algorithm has been installed.
         // SAFETY: Install must only be invoked once (such that the flash pointer
         // and function table share the same generics).
-        unsafe { &*(FLASH_POINTER as *const _) }
+        unsafe { &*(core::ptr::read_volatile(flash_pointer_address() as *const usize) as *const _) }
     }
 
     /// Retrieves flash from the mutex and invokes the provided function.
     /// This function will panic if the mutex can not be acquired. It is
     /// expected that access to this function is guarded by the spinlock.
+    #[cfg(not(feature = "direct-flash"))]
     fn with_firmware_updater<R>(
         // buffer: &'buffer mut AlignedBuffer<WRITE_SIZE>,
         func: impl for<'updater, 'mutex> FnOnce(
@@ -181,11 +349,64 @@ impl<const FLASH_SIZE: usize> FlashAlgorithm<FLASH_SIZE> {
         func(&mut firmware_updater)
     }
 
+    /// Retrieves flash from the mutex and invokes the provided function with
+    /// the DFU partition. As with `with_firmware_updater`, access must be
+    /// guarded by the spinlock.
+    #[cfg(not(feature = "direct-flash"))]
+    fn with_dfu<R>(
+        func: impl for<'mutex> FnOnce(
+            &mut BlockingPartition<'mutex, NoopRawMutex, Flash<'static, FLASH, Async, FLASH_SIZE>>,
+        ) -> R,
+    ) -> R {
+        let flash = unwrap!(Self::flash()
+            .try_lock()
+            .map_err(|_| "Failed to acquire flash mutex"));
+        let mut config =
+            FirmwareUpdaterConfig::from_linkerfile_blocking(flash.deref(), flash.deref());
+        func(&mut config.dfu)
+    }
+
+    /// Retrieves flash from the mutex and invokes the provided function. As
+    /// with `with_firmware_updater`, access must be guarded by the spinlock.
+    fn with_flash<R>(func: impl FnOnce(&mut Flash<'static, FLASH, Async, FLASH_SIZE>) -> R) -> R {
+        let flash = unwrap!(Self::flash()
+            .try_lock()
+            .map_err(|_| "Failed to acquire flash mutex"));
+        flash.lock(|flash| func(&mut flash.borrow_mut()))
+    }
+
     extern "C" fn init(address: usize, _clock_or_zero: usize, operation: usize) -> usize {
         INIT_CALLED.store(true, Ordering::SeqCst);
+        // probe-rs reaches `init` through the table, so this can't catch a
+        // corrupted `init` entry (the MPU guards against that), only the
+        // other entries and the flash pointer.
+        if checksum() != stored_checksum() {
+            warn!("Function table corrupted, refusing to run");
+            return 1;
+        }
         match Operation::try_from(operation) {
             Ok(operation) => {
                 trace!("Init: {:#x}, {:?}", address, operation);
+                // The sectors erased (or to be erased) are carried over from
+                // the erase to the program operation (whatever runs between),
+                // but the application may have written to the partition since
+                // any previous session.
+                #[cfg(not(feature = "direct-flash"))]
+                if matches!(operation, Operation::Erase) {
+                    DFU_ERASES.clear();
+                }
+                // Only a new image replaces the sectors staged (and not yet
+                // applied) by a previous session.
+                #[cfg(feature = "direct-flash")]
+                if matches!(operation, Operation::Erase) {
+                    direct::clear();
+                }
+                // An approval given for a previous image doesn't extend to
+                // this one.
+                if matches!(operation, Operation::Erase) {
+                    crate::debug::socket::revoke_reboot();
+                }
+                progress::begin(&operation);
                 0
             }
             Err(_) => 1,
@@ -197,47 +418,413 @@ impl<const FLASH_SIZE: usize> FlashAlgorithm<FLASH_SIZE> {
             return 1;
         };
         trace!("Uninit: {:?}", operation);
-        match operation {
+        let result = match operation {
             Operation::Program => {
-                trace!("Marking updated");
-                Self::with_firmware_updater(|updater| {
-                    updater.mark_updated().map_or_else(
-                        |e| {
-                            warn!("Failed to mark updated: {:?}", e);
-                            1
-                        },
-                        |_| 0,
-                    )
-                })
+                let skipped = progress::progress().sectors_skipped;
+                info!("Skipped {} unchanged sectors", skipped);
+                Self::finish_programming()
             }
             _ => 0,
+        };
+        progress::end();
+        result
+    }
+
+    #[cfg(not(feature = "direct-flash"))]
+    fn finish_programming() -> usize {
+        if Self::flush_pending_erases() != 0 {
+            1
+        } else {
+            Self::mark_updated()
         }
     }
 
+    /// The staged sectors are copied over the image by the debug server once
+    /// the session ends, see [`direct::apply`].
+    #[cfg(feature = "direct-flash")]
+    fn finish_programming() -> usize {
+        info!("Staged {} sectors", direct::staged_count());
+        0
+    }
+
+    /// Performs the erases deferred by `erase_sector` for sectors which
+    /// weren't then programmed.
+    #[cfg(not(feature = "direct-flash"))]
+    fn flush_pending_erases() -> usize {
+        Self::with_dfu(|dfu| DFU_ERASES.finish(dfu)).map_or_else(
+            |e| {
+                warn!("Failed to erase sector: {:?}", e);
+                progress::error(embassy_rp::flash::FLASH_BASE as u32);
+                1
+            },
+            |_| 0,
+        )
+    }
+
+    #[cfg(not(any(feature = "signed-updates", feature = "direct-flash")))]
+    fn mark_updated() -> usize {
+        trace!("Marking updated");
+        Self::with_firmware_updater(|updater| {
+            updater.mark_updated().map_or_else(
+                |e| {
+                    warn!("Failed to mark updated: {:?}", e);
+                    1
+                },
+                |_| 0,
+            )
+        })
+    }
+
+    /// Marks the update once both the image and its signature have been
+    /// received, if the signature verifies.
+    #[cfg(feature = "signed-updates")]
+    fn mark_updated() -> usize {
+        let Some(public_key) = signature::public_key() else {
+            warn!("No public key configured, refusing to mark updated");
+            return 1;
+        };
+        let (update_len, Some(signature)) = (signature::update_len(), signature::signature())
+        else {
+            trace!("Awaiting signature");
+            return 0;
+        };
+        if update_len == 0 {
+            trace!("Awaiting firmware");
+            return 0;
+        }
+        trace!("Verifying {} bytes and marking updated", update_len);
+        let result = Self::with_firmware_updater(|updater| {
+            updater.verify_and_mark_updated(&public_key, &signature, update_len)
+        });
+        signature::clear();
+        result.map_or_else(
+            |e| {
+                warn!("Failed to verify update: {:?}", e);
+                1
+            },
+            |_| 0,
+        )
+    }
+
     extern "C" fn program_page(address: usize, count: usize, buffer: usize) -> usize {
-        let address = address - embassy_rp::flash::FLASH_BASE as usize;
+        match buffer {
+            SCRATCH_A_ADDRESS => trace!("Programming from SCRATCH_A"),
+            SCRATCH_B_ADDRESS => trace!("Programming from SCRATCH_B"),
+            _ => trace!("Programming from {:#x}", buffer),
+        }
         let buffer = buffer as *const u8;
         let buffer = unsafe { core::slice::from_raw_parts(buffer, count) };
+        let offset = address - embassy_rp::flash::FLASH_BASE as usize;
 
+        let result = match routing::target(address as u32..(address + count) as u32) {
+            RouteTarget::Firmware => {
+                #[cfg(feature = "signed-updates")]
+                signature::record_firmware(offset, count);
+                Self::program_firmware(offset, buffer)
+            }
+            RouteTarget::Partition => Self::program_partition(offset, buffer),
+            RouteTarget::Reject => {
+                warn!("Rejected {:#x} to {:#x}", address, address + count);
+                REJECTED
+            }
+            #[cfg(feature = "signed-updates")]
+            RouteTarget::Signature => {
+                trace!("Received signature at {:#x}", address);
+                if signature::set_signature(buffer) {
+                    0
+                } else {
+                    warn!("Signature too short");
+                    1
+                }
+            }
+        };
+        progress::programmed(address as u32, count as u32, result == 0);
+        result
+    }
+
+    #[cfg(not(feature = "direct-flash"))]
+    fn program_firmware(address: usize, buffer: &[u8]) -> usize {
+        let count = buffer.len();
         trace!(
             "Programming {:#x} to {:#x}",
             address,
             address + count
         );
-        Self::with_firmware_updater(|updater| {
-            updater.write_firmware(address, buffer).map_or_else(
+        // As checked by `BlockingFirmwareUpdater::write_firmware`, the DFU
+        // partition may hold the previous image until the update is confirmed.
+        let booted = Self::with_firmware_updater(|updater| {
+            matches!(updater.get_state(), Ok(embassy_boot_rp::State::Boot))
+        });
+        if !booted {
+            warn!("Update not yet confirmed, refusing to write firmware");
+            return 1;
+        }
+        match Self::with_dfu(|dfu| DFU_ERASES.program(dfu, address, buffer)) {
+            Ok(true) => 0,
+            Ok(false) => {
+                trace!("Unchanged, skipping");
+                progress::skipped();
+                0
+            }
+            Err(e) => {
+                warn!("Failed to write firmware: {:?}", e);
+                1
+            }
+        }
+    }
+
+    /// Writes the data to the staging area, unless the image already holds it.
+    #[cfg(feature = "direct-flash")]
+    fn program_firmware(offset: usize, buffer: &[u8]) -> usize {
+        let offsets = offset..offset + buffer.len();
+        trace!("Staging {:#x} to {:#x}", offsets.start, offsets.end);
+        if !direct::stageable(&offsets) {
+            warn!("{:#x} is beyond the staging area", offsets.end);
+            return REJECTED;
+        }
+        let staging = direct::staging_offset();
+        Self::with_flash(|flash| {
+            let mut actual = [0; 256];
+            let unchanged = buffer
+                .chunks(actual.len())
+                .enumerate()
+                .all(|(i, expected)| {
+                    let chunk = offset + i * actual.len();
+                    let actual = &mut actual[..expected.len()];
+                    flash.blocking_read(chunk as u32, actual).is_ok() && actual == expected
+                });
+            if unchanged {
+                trace!("Unchanged, skipping");
+                progress::skipped();
+                direct::stage(offsets.clone(), false);
+                return Ok(());
+            }
+            direct::stage(offsets.clone(), true);
+            flash.blocking_write((staging + offset) as u32, buffer)
+        })
+        .map_or_else(
+            |e| {
+                warn!("Failed to stage firmware: {:?}", e);
+                1
+            },
+            |_| 0,
+        )
+    }
+
+    /// Writes the data in place, erasing the sectors first unless they're blank.
+    fn program_partition(offset: usize, buffer: &[u8]) -> usize {
+        trace!(
+            "Programming partition {:#x} to {:#x}",
+            offset,
+            offset + buffer.len()
+        );
+        let (start, end) = (offset as u32, (offset + buffer.len()) as u32);
+        Self::with_flash(|flash| {
+            let mut data = [0; 256];
+            for chunk in (start..end).step_by(data.len()) {
+                let data = &mut data[..core::cmp::min(256, end - chunk) as usize];
+                flash.blocking_read(chunk, data)?;
+                if data.iter().any(|byte| *byte != 0xff) {
+                    flash.blocking_erase(start, end)?;
+                    break;
+                }
+            }
+            flash.blocking_write(start, buffer)
+        })
+        .map_or_else(
+            |e| {
+                warn!("Failed to write partition: {:?}", e);
+                1
+            },
+            |_| 0,
+        )
+    }
+
+    extern "C" fn erase_sector(address: usize, _: usize, _: usize) -> usize {
+        let result = Self::erase(address);
+        progress::erased(address as u32, ERASE_SIZE as u32, result == 0);
+        result
+    }
+
+    fn erase(address: usize) -> usize {
+        trace!("Erasing sector at {:#x}", address);
+        let offset = address - embassy_rp::flash::FLASH_BASE as usize;
+        match routing::target(address as u32..(address + ERASE_SIZE) as u32) {
+            RouteTarget::Firmware => Self::erase_firmware(offset),
+            RouteTarget::Partition => Self::with_flash(|flash| {
+                flash.blocking_erase(offset as u32, (offset + ERASE_SIZE) as u32)
+            })
+            .map_or_else(
                 |e| {
-                    warn!("Failed to write firmware: {:?}", e);
+                    warn!("Failed to erase sector: {:?}", e);
                     1
                 },
                 |_| 0,
+            ),
+            RouteTarget::Reject => {
+                warn!("Rejected erasing {:#x}", address);
+                REJECTED
+            }
+            // The signature is held in RAM.
+            #[cfg(feature = "signed-updates")]
+            RouteTarget::Signature => 0,
+        }
+    }
+
+    #[cfg(not(feature = "direct-flash"))]
+    fn erase_firmware(offset: usize) -> usize {
+        match Self::with_dfu(|dfu| DFU_ERASES.erase(dfu, offset)) {
+            Ok(deferred) => {
+                if deferred {
+                    trace!("Deferring erase");
+                }
+                0
+            }
+            Err(e) => {
+                warn!("Failed to erase sector: {:?}", e);
+                1
+            }
+        }
+    }
+
+    /// Erases the sector of the staging area, which is then copied over the
+    /// image unless programmed with the data the image already holds.
+    #[cfg(feature = "direct-flash")]
+    fn erase_firmware(offset: usize) -> usize {
+        let offsets = offset..offset + ERASE_SIZE;
+        if !direct::stageable(&offsets) {
+            warn!("{:#x} is beyond the staging area", offset);
+            return REJECTED;
+        }
+        let staged = direct::staging_offset() + offset;
+        Self::with_flash(|flash| flash.blocking_erase(staged as u32, (staged + ERASE_SIZE) as u32))
+            .map_or_else(
+                |e| {
+                    warn!("Failed to erase sector: {:?}", e);
+                    1
+                },
+                |_| {
+                    direct::stage(offsets, true);
+                    0
+                },
             )
-        })
     }
 
-    extern "C" fn erase_sector(address: usize, _: usize, _: usize) -> usize {
-        trace!("Erasing sector at {:#x}", address);
-        // erasing is performed as part of program_page
-        0
+    #[cfg(not(feature = "direct-flash"))]
+    extern "C" fn erase_all(_: usize, _: usize, _: usize) -> usize {
+        trace!("Erasing DFU partition");
+        let (size, result) = Self::with_dfu(|dfu| (dfu.capacity(), DFU_ERASES.erase_all(dfu)));
+        progress::erased(
+            embassy_rp::flash::FLASH_BASE as u32,
+            size as u32,
+            result.is_ok(),
+        );
+        result.map_or_else(
+            |e| {
+                warn!("Failed to erase partition: {:?}", e);
+                1
+            },
+            |_| 0,
+        )
+    }
+
+    /// Erases the staging area. Only the sectors then programmed are copied
+    /// over the image, the rest of it isn't erased.
+    #[cfg(feature = "direct-flash")]
+    extern "C" fn erase_all(_: usize, _: usize, _: usize) -> usize {
+        trace!("Erasing staging area");
+        let staging = direct::staging_offset();
+        let result =
+            Self::with_flash(|flash| flash.blocking_erase(staging as u32, FLASH_SIZE as u32));
+        progress::erased(
+            embassy_rp::flash::FLASH_BASE as u32,
+            (FLASH_SIZE - staging) as u32,
+            result.is_ok(),
+        );
+        direct::clear();
+        result.map_or_else(
+            |e| {
+                warn!("Failed to erase staging area: {:?}", e);
+                1
+            },
+            |_| 0,
+        )
+    }
+
+    /// Compares the data against the DFU partition (or the routed partition),
+    /// returning the address following the data if it matches or the address
+    /// of the first mismatch.
+    extern "C" fn verify(address: usize, count: usize, buffer: usize) -> usize {
+        let offset = address - embassy_rp::flash::FLASH_BASE as usize;
+        let buffer = buffer as *const u8;
+        let expected = unsafe { core::slice::from_raw_parts(buffer, count) };
+
+        trace!("Verifying {:#x} to {:#x}", address, address + count);
+        let result = match routing::target(address as u32..(address + count) as u32) {
+            #[cfg(not(feature = "direct-flash"))]
+            RouteTarget::Firmware => Self::with_dfu(|dfu| {
+                compare(address, expected, |chunk_offset, actual| {
+                    dfu.read((offset + chunk_offset) as u32, actual)
+                })
+            }),
+            // Unchanged sectors aren't staged, so are compared with the image.
+            #[cfg(feature = "direct-flash")]
+            RouteTarget::Firmware => Self::with_flash(|flash| {
+                let offsets = offset..offset + count;
+                let source = if direct::stageable(&offsets) && direct::staged(offsets) {
+                    direct::staging_offset() + offset
+                } else {
+                    offset
+                };
+                compare(address, expected, |chunk_offset, actual| {
+                    flash.blocking_read((source + chunk_offset) as u32, actual)
+                })
+            }),
+            RouteTarget::Partition => Self::with_flash(|flash| {
+                compare(address, expected, |chunk_offset, actual| {
+                    flash.blocking_read((offset + chunk_offset) as u32, actual)
+                })
+            }),
+            RouteTarget::Reject => {
+                warn!("Rejected verifying {:#x}", address);
+                address
+            }
+            #[cfg(feature = "signed-updates")]
+            RouteTarget::Signature => {
+                let signature = signature::signature().unwrap_or([0xff; signature::SIGNATURE_SIZE]);
+                compare(address, expected, |chunk_offset, actual| {
+                    for (i, byte) in actual.iter_mut().enumerate() {
+                        *byte = *signature.get(chunk_offset + i).unwrap_or(&0xff);
+                    }
+                    Ok::<_, ()>(())
+                })
+            }
+        };
+        progress::verified(address as u32, result == address + count);
+        result
+    }
+}
+
+/// Compares `expected` with the data returned by `read` (given the offset from
+/// `address`), returning the address following the data if it matches or the
+/// address of the first mismatch.
+fn compare<E: Format>(
+    address: usize,
+    expected: &[u8],
+    mut read: impl FnMut(usize, &mut [u8]) -> Result<(), E>,
+) -> usize {
+    let mut actual = [0; 256];
+    for (i, expected) in expected.chunks(actual.len()).enumerate() {
+        let chunk_offset = i * actual.len();
+        let actual = &mut actual[..expected.len()];
+        if let Err(e) = read(chunk_offset, actual) {
+            warn!("Failed to read: {:?}", e);
+            return address + chunk_offset;
+        }
+        if let Some(mismatch) = actual.iter().zip(expected).position(|(a, e)| a != e) {
+            warn!("Mismatch at {:#x}", address + chunk_offset + mismatch);
+            return address + chunk_offset + mismatch;
+        }
     }
+    address + expected.len()
 }
@@ -0,0 +1,275 @@
+//! Deferral of the DFU erases requested by probe-rs, such that sectors which already hold the
+//! data being flashed are neither erased nor written.
+//!
+//! `erase_sector` only records the erase, which `program_page` then skips (along with the write)
+//! if the sector is unchanged. Sectors which aren't programmed are erased by [`finish`], once
+//! programming completes. Sectors beyond those tracked are erased immediately.
+//!
+//! Only sectors which the DFU partition already holds at the same offset are skipped, which in
+//! practice means repeating a flash of the same image (e.g. after an interrupted session). Once
+//! embassy-boot has swapped an update in, the DFU partition holds the previous image shifted by
+//! a page (active page `k` is copied to DFU page `k + 1`), so even an image barely changed since
+//! then is written in full. Comparing against the active partition wouldn't help, as the swap
+//! needs the whole update in the DFU partition.
+//!
+//! [`finish`]: DeferredErases::finish
+use core::ops::Range;
+
+use embassy_rp::flash::ERASE_SIZE;
+use embedded_storage::nor_flash::NorFlash;
+
+use super::algorithm::SectorSet;
+
+pub(super) struct DeferredErases {
+    /// The sectors known to be erased, which can be written without erasing them again.
+    erased: SectorSet,
+    /// The sectors to be erased, unless programmed with the data they already hold.
+    pending: SectorSet,
+}
+
+impl DeferredErases {
+    pub(super) const fn new() -> Self {
+        Self {
+            erased: SectorSet::new(),
+            pending: SectorSet::new(),
+        }
+    }
+
+    /// Forget the sectors erased (or to be erased), as the flash may have been written since.
+    pub(super) fn clear(&self) {
+        self.erased.clear();
+        self.pending.clear();
+    }
+
+    /// Erase the sector at `offset`, returning whether the erase was deferred.
+    pub(super) fn erase<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        offset: usize,
+    ) -> Result<bool, F::Error> {
+        let offsets = offset..offset + ERASE_SIZE;
+        if SectorSet::tracks(&offsets) {
+            self.pending.mark(offsets, true);
+            return Ok(true);
+        }
+        flash.erase(offsets.start as u32, offsets.end as u32)?;
+        Ok(false)
+    }
+
+    /// Erase the whole of `flash`.
+    pub(super) fn erase_all<F: NorFlash>(&self, flash: &mut F) -> Result<(), F::Error> {
+        let size = flash.capacity();
+        flash.erase(0, size as u32)?;
+        self.erased.mark(0..size, true);
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Write `data` at `offset`, erasing the sectors spanned first unless already erased,
+    /// returning whether it was written rather than skipped as unchanged.
+    pub(super) fn program<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        offset: usize,
+        data: &[u8],
+    ) -> Result<bool, F::Error> {
+        let offsets = offset..offset + data.len();
+        let unchanged = Self::holds(flash, offset, data);
+        self.pending.mark(offsets.clone(), false);
+        if unchanged {
+            return Ok(false);
+        }
+        if !self.erased.contains(offsets.clone()) {
+            let sectors = Self::sectors(&offsets);
+            flash.erase(sectors.start as u32, sectors.end as u32)?;
+        }
+        self.erased.mark(offsets, false);
+        flash.write(offset as u32, data)?;
+        Ok(true)
+    }
+
+    /// Perform the erases deferred for sectors which weren't then programmed, once programming
+    /// completes.
+    pub(super) fn finish<F: NorFlash>(&self, flash: &mut F) -> Result<(), F::Error> {
+        let result = self
+            .pending
+            .sectors()
+            .try_for_each(|offset| flash.erase(offset as u32, (offset + ERASE_SIZE) as u32));
+        self.clear();
+        result
+    }
+
+    /// Whether `flash` already holds `data` at `offset`.
+    fn holds<F: NorFlash>(flash: &mut F, offset: usize, data: &[u8]) -> bool {
+        let mut actual = [0; 256];
+        data.chunks(actual.len()).enumerate().all(|(i, expected)| {
+            let actual = &mut actual[..expected.len()];
+            flash
+                .read((offset + i * actual.len()) as u32, actual)
+                .is_ok()
+                && actual == expected
+        })
+    }
+
+    /// The sectors spanning `offsets`.
+    fn sectors(offsets: &Range<usize>) -> Range<usize> {
+        offsets.start / ERASE_SIZE * ERASE_SIZE..offsets.end.div_ceil(ERASE_SIZE) * ERASE_SIZE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use embedded_storage::nor_flash::{ErrorType, NorFlashErrorKind, ReadNorFlash};
+
+    const SECTORS: usize = 4;
+
+    /// NOR flash in RAM, counting the erases.
+    struct RamFlash {
+        data: Vec<u8>,
+        erases: usize,
+    }
+
+    impl RamFlash {
+        fn new() -> Self {
+            Self {
+                data: vec![0; SECTORS * ERASE_SIZE],
+                erases: 0,
+            }
+        }
+
+        fn sector(&self, index: usize) -> &[u8] {
+            &self.data[index * ERASE_SIZE..(index + 1) * ERASE_SIZE]
+        }
+    }
+
+    impl ErrorType for RamFlash {
+        type Error = NorFlashErrorKind;
+    }
+
+    impl ReadNorFlash for RamFlash {
+        const READ_SIZE: usize = 1;
+
+        fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+            Ok(())
+        }
+
+        fn capacity(&self) -> usize {
+            self.data.len()
+        }
+    }
+
+    impl NorFlash for RamFlash {
+        const WRITE_SIZE: usize = 1;
+        const ERASE_SIZE: usize = ERASE_SIZE;
+
+        fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+            assert!(from as usize % ERASE_SIZE == 0 && to as usize % ERASE_SIZE == 0);
+            self.data[from as usize..to as usize].fill(0xff);
+            self.erases += 1;
+            Ok(())
+        }
+
+        fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+            let offset = offset as usize;
+            let target = &mut self.data[offset..offset + bytes.len()];
+            assert!(target.iter().all(|byte| *byte == 0xff), "not erased");
+            target.copy_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    fn page(value: u8) -> Vec<u8> {
+        vec![value; ERASE_SIZE]
+    }
+
+    #[test]
+    fn deferred_erase_of_unchanged_sector() {
+        let (mut flash, erases) = (RamFlash::new(), DeferredErases::new());
+        flash.data[..ERASE_SIZE].fill(0x5a);
+        assert_eq!(erases.erase(&mut flash, 0), Ok(true));
+        assert_eq!(erases.program(&mut flash, 0, &page(0x5a)), Ok(false));
+        assert_eq!(erases.finish(&mut flash), Ok(()));
+        assert_eq!(flash.sector(0), page(0x5a));
+        assert_eq!(flash.erases, 0);
+    }
+
+    #[test]
+    fn deferred_erase_of_changed_sector() {
+        let (mut flash, erases) = (RamFlash::new(), DeferredErases::new());
+        flash.data[..ERASE_SIZE].fill(0x5a);
+        assert_eq!(erases.erase(&mut flash, 0), Ok(true));
+        assert_eq!(flash.erases, 0);
+        assert_eq!(erases.program(&mut flash, 0, &page(0xa5)), Ok(true));
+        assert_eq!(flash.erases, 1);
+        // The sector isn't erased again.
+        assert_eq!(erases.finish(&mut flash), Ok(()));
+        assert_eq!(flash.sector(0), page(0xa5));
+        assert_eq!(flash.erases, 1);
+    }
+
+    #[test]
+    fn erased_then_written() {
+        let (mut flash, erases) = (RamFlash::new(), DeferredErases::new());
+        assert_eq!(erases.erase_all(&mut flash), Ok(()));
+        assert_eq!(flash.erases, 1);
+        assert_eq!(
+            erases.program(&mut flash, ERASE_SIZE, &page(0xa5)),
+            Ok(true)
+        );
+        assert_eq!(flash.erases, 1);
+        // Written sectors are no longer known to be erased.
+        assert_eq!(
+            erases.program(&mut flash, ERASE_SIZE, &page(0x5a)),
+            Ok(true)
+        );
+        assert_eq!(flash.erases, 2);
+        assert_eq!(flash.sector(1), page(0x5a));
+        assert_eq!(flash.sector(2), page(0xff));
+    }
+
+    #[test]
+    fn swapped_image_is_rewritten() {
+        let (mut flash, erases) = (RamFlash::new(), DeferredErases::new());
+        // The previous image, as left by a swap one page along.
+        let image = [page(0x11), page(0x22)];
+        flash.data[ERASE_SIZE..2 * ERASE_SIZE].copy_from_slice(&image[0]);
+        flash.data[2 * ERASE_SIZE..3 * ERASE_SIZE].copy_from_slice(&image[1]);
+        for (sector, data) in image.iter().enumerate() {
+            assert_eq!(erases.erase(&mut flash, sector * ERASE_SIZE), Ok(true));
+            assert_eq!(
+                erases.program(&mut flash, sector * ERASE_SIZE, data),
+                Ok(true)
+            );
+        }
+        assert_eq!(flash.sector(0), page(0x11));
+        assert_eq!(flash.sector(1), page(0x22));
+    }
+
+    #[test]
+    fn finish_flushes_pending_erases() {
+        let (mut flash, erases) = (RamFlash::new(), DeferredErases::new());
+        flash.data.fill(0x5a);
+        for sector in 0..SECTORS {
+            assert_eq!(erases.erase(&mut flash, sector * ERASE_SIZE), Ok(true));
+        }
+        assert_eq!(
+            erases.program(&mut flash, ERASE_SIZE, &page(0xa5)),
+            Ok(true)
+        );
+        assert_eq!(erases.finish(&mut flash), Ok(()));
+        assert_eq!(flash.erases, SECTORS);
+        assert_eq!(flash.sector(0), page(0xff));
+        assert_eq!(flash.sector(1), page(0xa5));
+        assert_eq!(flash.sector(2), page(0xff));
+        assert_eq!(flash.sector(3), page(0xff));
+        // Nothing remains pending, or known to be erased.
+        assert_eq!(erases.finish(&mut flash), Ok(()));
+        assert_eq!(flash.erases, SECTORS);
+        assert_eq!(erases.program(&mut flash, 0, &page(0xa5)), Ok(true));
+        assert_eq!(flash.erases, SECTORS + 1);
+    }
+}
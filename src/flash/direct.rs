@@ -0,0 +1,170 @@
+//! Direct flashing of the running image (the `direct-flash` feature), for development builds
+//! without a bootloader.
+//!
+//! The flash algorithm can't overwrite the image it (and the debug server) runs from, so the
+//! image is staged in the upper half of flash instead, tracking the sectors written or erased.
+//! Once the reboot policy allows, [`apply`] copies the staged sectors over the image from RAM
+//! and resets. Until then (e.g. if power is lost) the previous image keeps running, but a power
+//! loss during the copy leaves a partial image, recoverable with BOOTSEL.
+//!
+//! Only the `BOOT2`, `FLASH`, `OTA_ALGORITHM`, `RAM`, `SCRATCH_A` and `SCRATCH_B` regions are
+//! required in memory.x. The image must fit in the lower half of flash (and its first 1MiB), and
+//! routed partitions must not overlap the upper half.
+use core::arch::asm;
+use core::ops::Range;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use defmt::info;
+use embassy_futures::yield_now;
+use embassy_rp::flash::{ERASE_SIZE, FLASH_BASE};
+use embassy_rp::{pac, rom_data};
+
+use super::algorithm::{SectorSet, MAX_TRACKED_SECTORS};
+use super::layout::SCRATCH_A_ADDRESS;
+use super::spinlock::Spinlock30;
+use crate::reset::{self, ResetReason};
+
+const STAGED_WORDS: usize = MAX_TRACKED_SECTORS / 32;
+
+/// The image sectors to be copied from the staging area.
+static STAGED_SECTORS: SectorSet = SectorSet::new();
+
+/// The offset of the staging area, set when the flash algorithm is installed.
+static STAGING_OFFSET: AtomicU32 = AtomicU32::new(0);
+
+/// The erase command passed to the bootrom, as used by embassy-rp.
+const BLOCK_SIZE: u32 = 1 << 16;
+const BLOCK_ERASE_CMD: u8 = 0xd8;
+
+const WATCHDOG_CTRL_TRIGGER: u32 = 1 << 31;
+
+const SECTOR_SHIFT: u32 = ERASE_SIZE.trailing_zeros();
+const _: () = assert!(1 << SECTOR_SHIFT == ERASE_SIZE);
+
+pub(super) fn set_staging_offset(offset: usize) {
+    STAGING_OFFSET.store(offset as u32, Ordering::Relaxed);
+}
+
+pub(super) fn staging_offset() -> usize {
+    STAGING_OFFSET.load(Ordering::Relaxed) as usize
+}
+
+/// Whether the image sectors spanning `offsets` can be staged.
+pub(super) fn stageable(offsets: &Range<usize>) -> bool {
+    SectorSet::tracks(offsets) && offsets.end <= staging_offset()
+}
+
+/// Marks the image sectors spanning `offsets` as staged, or not if the image already holds the
+/// data.
+pub(super) fn stage(offsets: Range<usize>, staged: bool) {
+    STAGED_SECTORS.mark(offsets, staged);
+}
+
+/// Whether all the image sectors spanning `offsets` are staged.
+pub(super) fn staged(offsets: Range<usize>) -> bool {
+    STAGED_SECTORS.contains(offsets)
+}
+
+pub(super) fn staged_count() -> usize {
+    STAGED_SECTORS.sectors().count()
+}
+
+pub(super) fn clear() {
+    STAGED_SECTORS.clear();
+}
+
+/// The bootrom functions used by [`copy_staged`], looked up whilst XIP is enabled.
+struct RomFunctions {
+    connect_internal_flash: unsafe extern "C" fn(),
+    flash_exit_xip: unsafe extern "C" fn(),
+    flash_range_erase: unsafe extern "C" fn(u32, usize, u32, u8),
+    flash_range_program: unsafe extern "C" fn(u32, *const u8, usize),
+    flash_flush_cache: unsafe extern "C" fn(),
+    flash_enter_cmd_xip: unsafe extern "C" fn(),
+}
+
+/// Copy the staged sectors over the image and reset, recording [`ResetReason::Ota`]. Must be
+/// invoked on core1, outside of a debug session.
+pub(crate) async fn apply() -> ! {
+    // Wait for any flash operation on core0 to complete. The spinlock is held until the reset.
+    let _spinlock = loop {
+        if let Some(spinlock) = Spinlock30::try_claim() {
+            break spinlock;
+        }
+        yield_now().await;
+    };
+    info!("Copying {} staged sectors", staged_count());
+    let staged = STAGED_SECTORS.words();
+    let rom = RomFunctions {
+        connect_internal_flash: rom_data::connect_internal_flash::ptr(),
+        flash_exit_xip: rom_data::flash_exit_xip::ptr(),
+        flash_range_erase: rom_data::flash_range_erase::ptr(),
+        flash_range_program: rom_data::flash_range_program::ptr(),
+        flash_flush_cache: rom_data::flash_flush_cache::ptr(),
+        flash_enter_cmd_xip: rom_data::flash_enter_cmd_xip::ptr(),
+    };
+    reset::record(ResetReason::Ota, 0);
+    // Don't let the application's watchdog interrupt the copy. When triggered, reset everything
+    // but the oscillators (as `Watchdog::trigger_reset` does).
+    pac::WATCHDOG.ctrl().write(|w| w.set_enable(false));
+    pac::PSM
+        .wdsel()
+        .write_value(pac::psm::regs::Wdsel(0x0001_ffff & !0b11));
+    // Core0 may be executing from flash, hold it off until the reset.
+    pac::PSM.frce_off().modify(|w| w.set_proc0(true));
+    cortex_m::interrupt::disable();
+    // SAFETY: Nothing else executes from flash whilst it is rewritten, and the staging area (and
+    // SCRATCH_A) are no longer used by the flash algorithm.
+    unsafe {
+        copy_staged(
+            &rom,
+            &staged,
+            STAGING_OFFSET.load(Ordering::Relaxed),
+            pac::PSM.frce_off().as_ptr() as *mut u32,
+            pac::WATCHDOG.ctrl().as_ptr() as *mut u32,
+        )
+    }
+}
+
+/// Runs from RAM, as the image is overwritten (and XIP disabled whilst erasing and programming),
+/// so must avoid anything which calls into flash. Without optimisations even `ptr::add` and
+/// `write_volatile` are calls, so addresses are computed as integers and the registers written
+/// with `str`. Sectors are indexed by shifting, as thumbv6m divides (and multiplies with overflow
+/// checks) with compiler builtins in flash. Only the panics of failed overflow and alignment
+/// checks remain in flash, which can't be reached. Each sector is copied through SCRATCH_A.
+#[inline(never)]
+#[link_section = ".data.ram_func"]
+unsafe fn copy_staged(
+    rom: &RomFunctions,
+    staged: &[u32; STAGED_WORDS],
+    staging_offset: u32,
+    frce_off: *mut u32,
+    watchdog_ctrl: *mut u32,
+) -> ! {
+    let staged = staged as *const [u32; STAGED_WORDS] as usize;
+    let mut sector = 0;
+    while sector < MAX_TRACKED_SECTORS {
+        if *((staged + ((sector >> 5) << 2)) as *const u32) & (1 << (sector & 31)) != 0 {
+            let offset = sector << SECTOR_SHIFT;
+            let source = FLASH_BASE as usize + staging_offset as usize + offset;
+            let mut word = 0;
+            while word < ERASE_SIZE {
+                *((SCRATCH_A_ADDRESS + word) as *mut u32) = *((source + word) as *const u32);
+                word += 4;
+            }
+            (rom.connect_internal_flash)();
+            (rom.flash_exit_xip)();
+            (rom.flash_range_erase)(offset as u32, ERASE_SIZE, BLOCK_SIZE, BLOCK_ERASE_CMD);
+            (rom.flash_range_program)(offset as u32, SCRATCH_A_ADDRESS as *const u8, ERASE_SIZE);
+            (rom.flash_flush_cache)();
+            (rom.flash_enter_cmd_xip)();
+        }
+        sector += 1;
+    }
+    // Release core0, which is reset along with everything else.
+    asm!("str {}, [{}]", in(reg) 0u32, in(reg) frce_off);
+    asm!("str {}, [{}]", in(reg) WATCHDOG_CTRL_TRIGGER, in(reg) watchdog_ctrl);
+    loop {
+        asm!("nop");
+    }
+}
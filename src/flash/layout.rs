@@ -0,0 +1,81 @@
+//! The layout of the RAM regions reserved for the flash algorithm.
+//!
+//! This file has no dependencies so that it can be shared with the host tools
+//! (see `tools/src/bin/target_yaml.rs`), which generate the probe-rs target
+//! description from the same constants.
+//!
+//! The region holding the trampolines, stack and function table is
+//! `OTA_ALGORITHM` in memory.x, whose bounds are found at runtime from the
+//! `__ota_algorithm_start` and `__ota_algorithm_end` symbols. The constants
+//! below describe the default region.
+
+/// The default base address of the RAM region reserved for the flash algorithm.
+pub const RESERVED_BASE_ADDRESS: usize = 0x20000000;
+/// The default size of the RAM region reserved for the flash algorithm.
+pub const RESERVED_SIZE: usize = 1024;
+/// The smallest reserved region, which holds the header, the instructions
+/// (rounded up to a word), the stack and the protected function table.
+pub const MIN_RESERVED_SIZE: usize =
+    PROBE_RS_HEADER_SIZE + INSTRUCTIONS_SIZE + STACK_SIZE + PROTECTED_SIZE;
+/// The largest reserved region, limited by the offset an `ldr` can load from.
+pub const MAX_RESERVED_SIZE: usize = PROBE_RS_HEADER_SIZE + 4 + 1020 + TABLE_SIZE;
+/// The size of the header probe-rs prefixes the instructions with.
+pub const PROBE_RS_HEADER_SIZE: usize = 4;
+/// The address at which probe-rs loads the instructions.
+pub const LOAD_ADDRESS: usize = load_address(RESERVED_BASE_ADDRESS);
+/// The stack size requested from probe-rs, placed after the instructions.
+pub const STACK_SIZE: usize = 512;
+/// The size of the instructions (an `ldr`/`b` pair per entry point and the
+/// call sequence), rounded up to a word.
+pub const INSTRUCTIONS_SIZE: usize = (2 * (2 * ENTRY_POINT_COUNT + 3)).next_multiple_of(4);
+/// The entry points, in the order of their function table entries.
+pub const ENTRY_POINTS: [&str; ENTRY_POINT_COUNT] = [
+    "pc_init",
+    "pc_uninit",
+    "pc_program_page",
+    "pc_erase_sector",
+    "pc_verify",
+    "pc_erase_all",
+];
+/// The number of entry points, each of which has an entry in the function table.
+pub const ENTRY_POINT_COUNT: usize = 6;
+/// The size of the function table used to store the (32-bit) pointers.
+pub const TABLE_SIZE: usize = 4 * ENTRY_POINT_COUNT;
+/// The size of the flash instance pointer and the checksum, which are stored
+/// immediately before the function table.
+pub const INTEGRITY_SIZE: usize = 8;
+/// The size of the end of the reserved region made read-only once installed,
+/// i.e. the flash instance pointer, the checksum and the function table.
+pub const PROTECTED_SIZE: usize = INTEGRITY_SIZE + TABLE_SIZE;
+/// The location of the function table in the reserved RAM region.
+pub const TABLE_BASE_ADDRESS: usize = table_base_address(RESERVED_BASE_ADDRESS, RESERVED_SIZE);
+
+/// The address at which probe-rs loads the instructions, for the reserved
+/// region at `base`.
+pub const fn load_address(base: usize) -> usize {
+    base + PROBE_RS_HEADER_SIZE
+}
+
+/// The lowest address of the stack probe-rs places after the instructions,
+/// for the reserved region at `base`.
+pub const fn stack_base_address(base: usize) -> usize {
+    load_address(base) + INSTRUCTIONS_SIZE
+}
+
+/// The location of the function table, at the end of the reserved region.
+pub const fn table_base_address(base: usize, size: usize) -> usize {
+    base + size - TABLE_SIZE
+}
+
+/// The location of the protected end of the reserved region, which starts
+/// with the flash instance pointer followed by the checksum.
+pub const fn protected_base_address(base: usize, size: usize) -> usize {
+    base + size - PROTECTED_SIZE
+}
+/// The size of the pages programmed by `pc_program_page`, the flash sector size.
+pub const PAGE_SIZE: usize = 4096;
+/// The first of the buffers probe-rs loads pages into (`SCRATCH_A` in memory.x). It alternates
+/// between the two, loading the next page whilst the previous one is programmed.
+pub const SCRATCH_A_ADDRESS: usize = 0x20040000;
+/// The second page buffer (`SCRATCH_B` in memory.x), which must follow the first.
+pub const SCRATCH_B_ADDRESS: usize = SCRATCH_A_ADDRESS + PAGE_SIZE;
@@ -1,2 +1,12 @@
 pub mod algorithm;
+#[cfg(not(feature = "direct-flash"))]
+mod deferred;
+#[cfg(feature = "direct-flash")]
+pub mod direct;
+pub mod layout;
+pub mod progress;
+pub mod protection;
+pub mod routing;
+#[cfg(feature = "signed-updates")]
+pub mod signature;
 pub mod spinlock;
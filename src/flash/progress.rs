@@ -0,0 +1,167 @@
+//! Progress of the flash algorithm, published for the application.
+//!
+//! Core0 is halted whilst the flash algorithm runs, so the progress is kept in atomics which can
+//! be read from core1 tasks (e.g. to show update progress on a display, or report it as
+//! telemetry) and is served by [`crate::debug::diagnostics`].
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use defmt::Format;
+
+use super::algorithm::Operation;
+
+/// The size of an encoded [`FlashProgress`].
+pub const FLASH_PROGRESS_SIZE: usize = 28;
+
+const NO_ADDRESS: u32 = u32::MAX;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum FlashState {
+    Idle = 0,
+    Erasing = 1,
+    Programming = 2,
+    Verifying = 3,
+}
+
+#[derive(Clone, Copy, Format)]
+pub struct FlashProgress {
+    /// The operation in progress, [`FlashState::Idle`] between operations.
+    pub state: FlashState,
+    /// The address most recently erased, programmed or verified.
+    pub address: u32,
+    pub bytes_erased: u32,
+    /// Including the sectors skipped as unchanged.
+    pub bytes_programmed: u32,
+    pub sectors_skipped: u32,
+    /// The number of failed erases, writes and verifications.
+    pub errors: u32,
+    /// The address of the most recent error.
+    pub error_address: Option<u32>,
+}
+
+impl FlashProgress {
+    /// Encode the progress in the (little endian) wire format:
+    ///
+    /// | bytes | field |
+    /// |-------|-------|
+    /// | 4 | state: idle (0), erasing (1), programming (2), verifying (3) |
+    /// | 4 | address |
+    /// | 4 | bytes erased |
+    /// | 4 | bytes programmed |
+    /// | 4 | sectors skipped |
+    /// | 4 | errors |
+    /// | 4 | error address, `0xffffffff` if none |
+    pub fn encode(&self) -> [u8; FLASH_PROGRESS_SIZE] {
+        let words = [
+            self.state as u32,
+            self.address,
+            self.bytes_erased,
+            self.bytes_programmed,
+            self.sectors_skipped,
+            self.errors,
+            self.error_address.unwrap_or(NO_ADDRESS),
+        ];
+        let mut data = [0; FLASH_PROGRESS_SIZE];
+        for (chunk, word) in data.chunks_exact_mut(4).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes());
+        }
+        data
+    }
+}
+
+static STATE: AtomicU32 = AtomicU32::new(FlashState::Idle as u32);
+static ADDRESS: AtomicU32 = AtomicU32::new(0);
+static BYTES_ERASED: AtomicU32 = AtomicU32::new(0);
+static BYTES_PROGRAMMED: AtomicU32 = AtomicU32::new(0);
+static SECTORS_SKIPPED: AtomicU32 = AtomicU32::new(0);
+static ERRORS: AtomicU32 = AtomicU32::new(0);
+static ERROR_ADDRESS: AtomicU32 = AtomicU32::new(NO_ADDRESS);
+/// Set once the debug session which flashed ends, such that the next operation starts afresh.
+static SESSION_ENDED: AtomicBool = AtomicBool::new(true);
+
+/// Only the flash algorithm writes, so the counters needn't be updated atomically.
+fn add(counter: &AtomicU32, value: u32) {
+    counter.store(counter.load(Ordering::Relaxed) + value, Ordering::Relaxed);
+}
+
+/// Start an operation. The first of a session resets the counters, whichever operation it is
+/// (e.g. if the session doesn't erase).
+pub(crate) fn begin(operation: &Operation) {
+    let state = match operation {
+        Operation::Erase => FlashState::Erasing,
+        Operation::Program => FlashState::Programming,
+        Operation::Verify => FlashState::Verifying,
+    };
+    if SESSION_ENDED.swap(false, Ordering::Relaxed) {
+        [&BYTES_ERASED, &BYTES_PROGRAMMED, &SECTORS_SKIPPED, &ERRORS]
+            .iter()
+            .for_each(|counter| counter.store(0, Ordering::Relaxed));
+        ERROR_ADDRESS.store(NO_ADDRESS, Ordering::Relaxed);
+    }
+    STATE.store(state as u32, Ordering::Relaxed);
+}
+
+pub(crate) fn end() {
+    STATE.store(FlashState::Idle as u32, Ordering::Relaxed);
+}
+
+/// End the session, once the debug connection running the operations closes.
+pub(crate) fn end_session() {
+    SESSION_ENDED.store(true, Ordering::Relaxed);
+}
+
+/// Record the outcome of erasing `len` bytes at `address`.
+pub(crate) fn erased(address: u32, len: u32, ok: bool) {
+    ADDRESS.store(address, Ordering::Relaxed);
+    if ok {
+        add(&BYTES_ERASED, len);
+    } else {
+        error(address);
+    }
+}
+
+/// Record the outcome of programming `len` bytes at `address`.
+pub(crate) fn programmed(address: u32, len: u32, ok: bool) {
+    ADDRESS.store(address, Ordering::Relaxed);
+    if ok {
+        add(&BYTES_PROGRAMMED, len);
+    } else {
+        error(address);
+    }
+}
+
+pub(crate) fn skipped() {
+    add(&SECTORS_SKIPPED, 1);
+}
+
+/// Record the outcome of verifying the data at `address`.
+pub(crate) fn verified(address: u32, ok: bool) {
+    ADDRESS.store(address, Ordering::Relaxed);
+    if !ok {
+        error(address);
+    }
+}
+
+pub(crate) fn error(address: u32) {
+    add(&ERRORS, 1);
+    ERROR_ADDRESS.store(address, Ordering::Relaxed);
+}
+
+/// The progress of the current (or most recent) flashing session.
+pub fn progress() -> FlashProgress {
+    let state = match STATE.load(Ordering::Relaxed) {
+        1 => FlashState::Erasing,
+        2 => FlashState::Programming,
+        3 => FlashState::Verifying,
+        _ => FlashState::Idle,
+    };
+    let error_address = ERROR_ADDRESS.load(Ordering::Relaxed);
+    FlashProgress {
+        state,
+        address: ADDRESS.load(Ordering::Relaxed),
+        bytes_erased: BYTES_ERASED.load(Ordering::Relaxed),
+        bytes_programmed: BYTES_PROGRAMMED.load(Ordering::Relaxed),
+        sectors_skipped: SECTORS_SKIPPED.load(Ordering::Relaxed),
+        errors: ERRORS.load(Ordering::Relaxed),
+        error_address: (error_address != NO_ADDRESS).then_some(error_address),
+    }
+}
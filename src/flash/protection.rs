@@ -0,0 +1,119 @@
+//! Write protection of the flash algorithm's reserved region.
+//!
+//! Once installed, the reserved region (the trampolines, the flash instance pointer, its
+//! checksum and the function table) is made read-only by MPU regions on each core. A stray write
+//! then faults (and can be caught by [`crate::debug::crash::CrashMonitor`]) rather than leaving
+//! probe-rs to jump into garbage. The debugger's own accesses bypass the MPU.
+//!
+//! ARMv6-M MPU regions are naturally aligned powers of two, so the reserved region is covered by
+//! the 1KiB (or 2KiB) region containing it, with only the subregions within it enabled. probe-rs
+//! runs the algorithm on core0 with its stack in the reserved region, so the subregions
+//! overlapping the stack (which, with 128 byte subregions, also hold the trampolines) are left
+//! writable. The protected end of the region is therefore also covered by a 256 byte region, whose
+//! 32 byte subregions never overlap the stack. The reserved region must end on such a subregion
+//! boundary, which is asserted in memory.x.
+use core::ops::Range;
+
+use cortex_m::asm::{dsb, isb};
+use cortex_m::peripheral::{mpu, MPU};
+
+use super::algorithm::reserved_region;
+use super::layout::{protected_base_address, stack_base_address, PROTECTED_SIZE, STACK_SIZE};
+
+/// The highest priority regions, overriding any configured by the application.
+const RESERVED_REGION: u32 = 6;
+const PROTECTED_REGION: u32 = 7;
+const PROTECTED_REGION_SIZE: usize = 256;
+/// The sizes which can cover the reserved region, smallest first.
+const RESERVED_REGION_SIZES: [usize; 2] = [1024, 2048];
+
+const CTRL_ENABLE: u32 = 1 << 0;
+/// Use the default memory map outside of the enabled regions.
+const CTRL_PRIVDEFENA: u32 = 1 << 2;
+
+const RASR_ENABLE: u32 = 1 << 0;
+const RASR_SIZE_SHIFT: u32 = 1;
+const RASR_SRD_SHIFT: u32 = 8;
+/// Shareable, cacheable and bufferable normal memory (as SRAM is by default).
+const RASR_ATTRIBUTES: u32 = 0b111 << 16;
+/// Read-only, privileged or not.
+const RASR_AP_READ_ONLY: u32 = 0b110 << 24;
+const RASR_XN: u32 = 1 << 28;
+
+const _: () = assert!(PROTECTED_SIZE == PROTECTED_REGION_SIZE / 8);
+
+/// The subregions (a bit each) of the `size` bytes at `base` which lie within `protected` and
+/// outside of `writable`.
+fn subregions(base: usize, size: usize, protected: &Range<usize>, writable: &Range<usize>) -> u32 {
+    let size = size / 8;
+    (0..8)
+        .filter(|i| {
+            let start = base + i * size;
+            let end = start + size;
+            protected.start <= start
+                && end <= protected.end
+                && (end <= writable.start || writable.end <= start)
+        })
+        .fold(0, |subregions, i| subregions | 1 << i)
+}
+
+/// Make the `size` bytes at `base` (naturally aligned) read-only, except for the subregions not
+/// `enabled`.
+///
+/// # Safety
+///
+/// Nothing may write to the enabled subregions once protected.
+unsafe fn protect_region(
+    mpu: &mpu::RegisterBlock,
+    region: u32,
+    base: usize,
+    size: usize,
+    enabled: u32,
+) {
+    mpu.rnr.write(region);
+    mpu.rbar.write(base as u32);
+    mpu.rasr.write(if enabled == 0 {
+        0
+    } else {
+        RASR_XN
+            | RASR_AP_READ_ONLY
+            | RASR_ATTRIBUTES
+            | (!enabled & 0xff) << RASR_SRD_SHIFT
+            // The region is 2^(SIZE + 1) bytes.
+            | (size.trailing_zeros() - 1) << RASR_SIZE_SHIFT
+            | RASR_ENABLE
+    });
+}
+
+/// Make the reserved region, but for the algorithm's stack, read-only for the calling core.
+pub(crate) fn protect() {
+    let reserved = reserved_region();
+    let protected = protected_base_address(reserved.start, reserved.len())..reserved.end;
+    let stack = stack_base_address(reserved.start);
+    let stack = stack..stack + STACK_SIZE;
+
+    // SAFETY: The MPU regions only restrict writes to the reserved region, outside of the
+    // algorithm's stack. The rest of it is only written by `FlashAlgorithm::install` (before this
+    // is called) and by the debugger.
+    unsafe {
+        let mpu = &*MPU::PTR;
+        let covering = RESERVED_REGION_SIZES.iter().find_map(|size| {
+            let base = reserved.start & !(size - 1);
+            (reserved.end <= base + size).then_some((base, *size))
+        });
+        match covering {
+            Some((base, size)) => {
+                let enabled = subregions(base, size, &reserved, &stack);
+                protect_region(mpu, RESERVED_REGION, base, size, enabled);
+            }
+            None => protect_region(mpu, RESERVED_REGION, 0, 0, 0),
+        }
+        let base = protected.start & !(PROTECTED_REGION_SIZE - 1);
+        let enabled = subregions(base, PROTECTED_REGION_SIZE, &protected, &stack);
+        protect_region(mpu, PROTECTED_REGION, base, PROTECTED_REGION_SIZE, enabled);
+        let ctrl = mpu.ctrl.read();
+        mpu.ctrl.write(ctrl | CTRL_PRIVDEFENA | CTRL_ENABLE);
+    }
+    dsb();
+    isb();
+}
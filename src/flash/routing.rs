@@ -0,0 +1,73 @@
+//! Routing of the flash algorithm's writes by address.
+//!
+//! By default every address is written to the DFU partition as part of the firmware image (i.e.
+//! at `address - FLASH_BASE` within the partition). A routing table can instead send ranges of
+//! addresses directly to flash, e.g. the cyw43 firmware blobs, or reject them outright.
+use core::ops::Range;
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use defmt::Format;
+
+#[derive(Clone, Copy, Debug, Format, PartialEq, Eq)]
+pub enum RouteTarget {
+    /// Written to the DFU partition, to be swapped in by the bootloader.
+    Firmware,
+    /// Written in place, e.g. a partition holding data rather than code. The range must be
+    /// sector aligned and must not overlap the running firmware, the DFU partition or the
+    /// bootloader state.
+    Partition,
+    /// Not written, failing the flash algorithm operation.
+    Reject,
+    /// Not written to flash, the start of the data is taken as the detached signature of the
+    /// firmware, see [`crate::OtaDebugger::set_public_key`].
+    #[cfg(feature = "signed-updates")]
+    Signature,
+}
+
+/// Routes the (XIP) addresses `range` to `target`.
+#[derive(Clone, Debug, Format, PartialEq, Eq)]
+pub struct Route {
+    pub range: Range<u32>,
+    pub target: RouteTarget,
+}
+
+impl Route {
+    pub const fn new(range: Range<u32>, target: RouteTarget) -> Self {
+        Self { range, target }
+    }
+}
+
+/// The routing table, empty until set.
+static ROUTES: AtomicPtr<Route> = AtomicPtr::new(core::ptr::null_mut());
+static ROUTE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the routing table. The caller must hold the spinlock, such that the flash algorithm
+/// isn't running.
+pub(crate) fn set(routes: &'static [Route]) {
+    ROUTE_COUNT.store(0, Ordering::SeqCst);
+    ROUTES.store(routes.as_ptr() as *mut _, Ordering::SeqCst);
+    ROUTE_COUNT.store(routes.len(), Ordering::SeqCst);
+}
+
+fn routes() -> &'static [Route] {
+    let routes = ROUTES.load(Ordering::SeqCst);
+    if routes.is_null() {
+        return &[];
+    }
+    // SAFETY: The pointer and count were taken from a static slice by `set`.
+    unsafe { core::slice::from_raw_parts(routes, ROUTE_COUNT.load(Ordering::SeqCst)) }
+}
+
+/// The target of the addresses `range`. Addresses not covered by a route are firmware, whereas
+/// those only partially covered (or spanning several routes) are rejected.
+pub(crate) fn target(range: Range<u32>) -> RouteTarget {
+    for route in routes() {
+        if route.range.start <= range.start && range.end <= route.range.end {
+            return route.target;
+        }
+        if route.range.start < range.end && range.start < route.range.end {
+            return RouteTarget::Reject;
+        }
+    }
+    RouteTarget::Firmware
+}
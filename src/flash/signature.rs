@@ -0,0 +1,70 @@
+//! State for signed updates (the `signed-updates` feature).
+//!
+//! The detached ed25519 signature is flashed to an address routed to
+//! [`RouteTarget::Signature`](super::routing::RouteTarget::Signature), either in the same
+//! probe-rs session as the image or a separate one. The update is only marked once both the
+//! image and its signature have been received and the signature verifies. The signature covers
+//! the image as written to the DFU partition, i.e. padded with `0xff` to a whole number of pages.
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use defmt::unwrap;
+
+pub const PUBLIC_KEY_SIZE: usize = 32;
+pub const SIGNATURE_SIZE: usize = 64;
+
+/// The key updates must be signed with, none until configured.
+static mut PUBLIC_KEY: Option<[u8; PUBLIC_KEY_SIZE]> = None;
+
+/// The signature received for the pending update.
+static mut SIGNATURE: Option<[u8; SIGNATURE_SIZE]> = None;
+
+/// The length of the image written to the DFU partition, zero if none.
+static UPDATE_LEN: AtomicU32 = AtomicU32::new(0);
+
+/// Set the public key. The caller must hold the spinlock, such that the flash algorithm isn't
+/// running.
+pub(crate) fn set_public_key(public_key: [u8; PUBLIC_KEY_SIZE]) {
+    // SAFETY: Guarded by the spinlock.
+    unsafe { PUBLIC_KEY = Some(public_key) };
+}
+
+pub(crate) fn public_key() -> Option<[u8; PUBLIC_KEY_SIZE]> {
+    // SAFETY: Only invoked by the flash algorithm, guarded by the spinlock.
+    unsafe { PUBLIC_KEY }
+}
+
+/// Store the signature from the start of `data`, returning whether it was long enough.
+pub(crate) fn set_signature(data: &[u8]) -> bool {
+    let Some(signature) = data.get(..SIGNATURE_SIZE) else {
+        return false;
+    };
+    // SAFETY: Only invoked by the flash algorithm, guarded by the spinlock.
+    unsafe { SIGNATURE = Some(unwrap!(signature.try_into())) };
+    true
+}
+
+pub(crate) fn signature() -> Option<[u8; SIGNATURE_SIZE]> {
+    // SAFETY: Only invoked by the flash algorithm, guarded by the spinlock.
+    unsafe { SIGNATURE }
+}
+
+/// Record a write of `len` bytes at `offset` within the DFU partition. A write to the start of
+/// the partition begins a new image.
+pub(crate) fn record_firmware(offset: usize, len: usize) {
+    let end = (offset + len) as u32;
+    let update_len = UPDATE_LEN.load(Ordering::Relaxed);
+    if offset == 0 || end > update_len {
+        UPDATE_LEN.store(end, Ordering::Relaxed);
+    }
+}
+
+pub(crate) fn update_len() -> u32 {
+    UPDATE_LEN.load(Ordering::Relaxed)
+}
+
+/// Forget the pending update, once marked (or rejected).
+pub(crate) fn clear() {
+    UPDATE_LEN.store(0, Ordering::Relaxed);
+    // SAFETY: Only invoked by the flash algorithm, guarded by the spinlock.
+    unsafe { SIGNATURE = None };
+}
@@ -1,33 +1,44 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 
 pub mod debug;
 mod flash;
+#[cfg(feature = "defmt-net")]
+pub mod logger;
+pub mod reset;
+pub mod stack;
 
+pub use flash::progress::{progress as flash_progress, FlashProgress, FlashState};
+pub use flash::routing::{Route, RouteTarget};
 pub use flash::spinlock::{try_with_spinlock, with_spinlock};
 
-use core::{
-    cell::RefCell,
-    ops::{Deref, DerefMut},
-};
+use core::{cell::RefCell, ops::DerefMut};
 
 use debug::socket::DebugSocket;
-use embassy_boot_rp::{AlignedBuffer, FirmwareUpdaterConfig};
-use embassy_embedded_hal::flash::partition::BlockingPartition;
 use embassy_executor::{Executor, Spawner};
 use embassy_rp::{
-    flash::{Async, Flash, WRITE_SIZE},
+    flash::{Async, Flash},
     multicore::{spawn_core1, Stack},
     peripherals::{CORE1, DMA_CH0, FLASH},
 };
 use embassy_sync::{
-    blocking_mutex::{
-        raw::{CriticalSectionRawMutex, NoopRawMutex},
-        NoopMutex,
-    },
+    blocking_mutex::{raw::CriticalSectionRawMutex, NoopMutex},
     mutex::Mutex,
 };
 use flash::algorithm::FlashAlgorithm;
 use static_cell::StaticCell;
+#[cfg(not(feature = "direct-flash"))]
+use {
+    core::{future::Future, ops::Deref},
+    defmt::{info, warn},
+    embassy_boot_rp::{AlignedBuffer, FirmwareUpdaterConfig, FirmwareUpdaterError},
+    embassy_embedded_hal::flash::partition::BlockingPartition,
+    embassy_rp::flash::WRITE_SIZE,
+    embassy_sync::blocking_mutex::raw::NoopRawMutex,
+    embassy_time::{with_timeout, Duration},
+};
+
+#[cfg(all(feature = "direct-flash", feature = "signed-updates"))]
+compile_error!("`signed-updates` requires the bootloader, so can't be used with `direct-flash`");
 
 pub struct State<const FLASH_SIZE: usize, const STACK_SIZE: usize> {
     core1_stack: Stack<STACK_SIZE>,
@@ -61,14 +72,20 @@ impl<const FLASH_SIZE: usize, const STACK_SIZE: usize> OtaDebugger<FLASH_SIZE, S
         // By accepting the singleton CORE1 peripheral we're ensuring that this function isn't called twice.
         // Therefore we're not going to overwrite any existing algorithm.
         FlashAlgorithm::install(&state.flash);
+        debug::tasks::register_executor(0, Spawner::for_current_executor().await);
+        stack::paint_core0();
+        stack::paint_core1(&mut state.core1_stack.mem);
 
         spawn_core1(
             core1,
             unsafe { &mut *core::ptr::addr_of_mut!(state.core1_stack) },
             move || {
+                // The MPU is per core, core0's was configured by `install`.
+                flash::protection::protect();
                 static EXECUTOR: StaticCell<Executor> = StaticCell::new();
                 let executor = EXECUTOR.init_with(|| Executor::new());
                 executor.run(|spawner| {
+                    debug::tasks::register_executor(1, spawner);
                     core1_init(spawner);
                 })
             },
@@ -99,6 +116,132 @@ impl<const FLASH_SIZE: usize, const STACK_SIZE: usize> OtaDebugger<FLASH_SIZE, S
         .await
     }
 
+    /// Route the flash algorithm's writes by address, e.g. to update data partitions in place
+    /// alongside the firmware. Addresses not covered by a route are written to the DFU partition
+    /// as firmware.
+    pub async fn set_routes(&self, routes: &'static [Route]) {
+        with_spinlock(|_| async { flash::routing::set(routes) }, ()).await
+    }
+
+    /// Require updates to be signed with the ed25519 key `public_key`. The detached signature is
+    /// flashed to an address routed to [`RouteTarget::Signature`], and the update is only marked
+    /// once it has been verified.
+    #[cfg(feature = "signed-updates")]
+    pub async fn set_public_key(&self, public_key: [u8; 32]) {
+        with_spinlock(
+            |_| async { flash::signature::set_public_key(public_key) },
+            (),
+        )
+        .await
+    }
+
+    /// Persist the crash dump captured by [`debug::crash::CrashMonitor`] before the last reset
+    /// (if any) to the `CRASH_DUMP` flash region, returning whether one was written. Call this
+    /// early after boot, before the RAM holding the dump is reused.
+    pub async fn persist_crash_dump(&self) -> Result<bool, embassy_rp::flash::Error> {
+        let Some(dump) = debug::crash::ram_dump() else {
+            return Ok(false);
+        };
+        let region = debug::crash::flash_region();
+        self.with_flash_blocking(|flash| {
+            flash.blocking_erase(region.start, region.end)?;
+            flash.blocking_write(region.start, dump.as_bytes())
+        })
+        .await?;
+        // Only once persisted, such that a failed write is retried after the next reset.
+        debug::crash::invalidate_ram_dump();
+        Ok(true)
+    }
+
+    /// The reason recorded before the last reset performed by this crate, or a watchdog
+    /// timeout. Remains available until cleared with [`Self::clear_reset_reason`].
+    pub fn reset_reason(&self) -> Option<reset::ResetReason> {
+        reset::reason()
+    }
+
+    /// The panic message recorded by [`reset::panic_reboot`], if the last reset was a panic.
+    pub fn panic_message(&self) -> Option<&'static str> {
+        reset::panic_message()
+    }
+
+    pub fn clear_reset_reason(&self) {
+        reset::clear()
+    }
+
+    /// The progress of the flash algorithm's current (or most recent) session. Whilst it runs
+    /// core0 is halted, so poll [`flash_progress`] from a core1 task to follow it.
+    pub fn flash_progress(&self) -> FlashProgress {
+        flash::progress::progress()
+    }
+
+    /// Whether the flash algorithm has been run (e.g. an update downloaded) and the reboot to
+    /// apply it is waiting on the [`debug::socket::RebootPolicy`].
+    pub fn update_pending(&self) -> bool {
+        debug::socket::update_pending()
+    }
+
+    /// Allow the pending reboot, for [`debug::socket::RebootPolicy::Approved`]. Approving whilst
+    /// an update is being flashed allows the reboot as soon as it is pending, but starting to
+    /// flash another image discards the approval.
+    pub fn approve_reboot(&self) {
+        debug::socket::approve_reboot()
+    }
+
+    /// The high-water mark of the core0 and core1 stacks, since this debugger was created.
+    pub fn stack_usage(&self) -> [stack::StackUsage; 2] {
+        stack::usage()
+    }
+
+    /// After an update has been swapped in, run the new image on trial: it is only marked booted
+    /// if `health_check` returns `true` within `deadline`, otherwise the device reboots and the
+    /// bootloader reverts to the previous image. Returns immediately if the image isn't on trial.
+    ///
+    /// The debug server keeps running throughout, but flashing is refused until the trial ends
+    /// (the DFU partition holding the image to revert to), so a broken image is only reflashed
+    /// once marked booted or reverted.
+    #[cfg(not(feature = "direct-flash"))]
+    pub async fn trial_boot(
+        &self,
+        deadline: Duration,
+        health_check: impl Future<Output = bool>,
+    ) -> Result<(), FirmwareUpdaterError> {
+        let state = self
+            .with_firmware_updater_blocking(|updater| updater.get_state())
+            .await?;
+        if !matches!(state, embassy_boot_rp::State::Swap) {
+            return Ok(());
+        }
+
+        info!("Trial boot, waiting for health checks");
+        match with_timeout(deadline, health_check).await {
+            Ok(true) => {
+                info!("Health checks passed, marking booted");
+                self.with_firmware_updater_blocking(|updater| updater.mark_booted())
+                    .await
+            }
+            Ok(false) => {
+                warn!("Health checks failed, reverting");
+                reset::reboot(reset::ResetReason::TrialFailed)
+            }
+            Err(_) => {
+                warn!("Health checks timed out, reverting");
+                reset::reboot(reset::ResetReason::TrialFailed)
+            }
+        }
+    }
+
+    /// Without a bootloader nothing runs on trial, so this returns immediately (without running
+    /// `health_check`), such that the application needn't depend on the `direct-flash` feature.
+    #[cfg(feature = "direct-flash")]
+    pub async fn trial_boot(
+        &self,
+        _deadline: embassy_time::Duration,
+        _health_check: impl core::future::Future<Output = bool>,
+    ) -> Result<(), embassy_boot_rp::FirmwareUpdaterError> {
+        Ok(())
+    }
+
+    #[cfg(not(feature = "direct-flash"))]
     pub async fn with_firmware_updater_blocking<R>(
         &self,
         func: impl for<'updater, 'mutex> FnOnce(
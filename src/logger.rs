@@ -0,0 +1,202 @@
+//! A `defmt` global logger which streams frames over TCP (enabled by the `defmt-net` feature).
+//!
+//! Each core encodes frames into its own single-producer ring buffer with interrupts disabled,
+//! only publishing a frame once it is complete. [`listen`] drains both rings to a connected
+//! client, e.g. `defmt-print -e <elf> tcp --host <device> --port 19021`.
+//!
+//! No lock is shared between the cores: neither [`crate::flash::spinlock::Spinlock30`] nor the
+//! critical section is taken when logging. A core halted by the debugger (even mid-frame)
+//! therefore never blocks the other core's logging, and logging never contends with flash
+//! operations.
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use defmt::Encoder;
+use embassy_net::{driver::Driver, tcp::TcpSocket};
+use embassy_rp::pac::SIO;
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+
+/// The default port used by `defmt-print tcp`.
+pub const DEFAULT_PORT: u16 = 19021;
+
+/// The size of each core's ring buffer.
+const BUFFER_SIZE: usize = 2048;
+
+/// The size of the chunks copied out of a ring to be sent.
+const CHUNK_SIZE: usize = 256;
+
+/// How often the rings are drained whilst a client is connected.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// State only accessed by the owning core, with interrupts disabled.
+struct Producer {
+    taken: bool,
+    restore_interrupts: bool,
+    /// The end of the frame currently being written.
+    head: usize,
+    /// The frame currently being written did not fit and will be discarded.
+    overflow: bool,
+}
+
+struct CoreState {
+    buffer: UnsafeCell<[u8; BUFFER_SIZE]>,
+    /// The end of the last complete frame, written by the producer.
+    write: AtomicUsize,
+    /// The end of the data sent to the client, written by the consumer.
+    read: AtomicUsize,
+    /// The number of frames discarded because the ring was full, written by the producer.
+    dropped: AtomicUsize,
+    producer: UnsafeCell<Producer>,
+    encoder: UnsafeCell<Encoder>,
+}
+
+// SAFETY: The producer state and encoder are only accessed by the owning core with interrupts
+// disabled, the buffer is partitioned between the producer and consumer by the atomic offsets.
+unsafe impl Sync for CoreState {}
+
+impl CoreState {
+    const fn new() -> Self {
+        Self {
+            buffer: UnsafeCell::new([0; BUFFER_SIZE]),
+            write: AtomicUsize::new(0),
+            read: AtomicUsize::new(0),
+            dropped: AtomicUsize::new(0),
+            producer: UnsafeCell::new(Producer {
+                taken: false,
+                restore_interrupts: false,
+                head: 0,
+                overflow: false,
+            }),
+            encoder: UnsafeCell::new(Encoder::new()),
+        }
+    }
+
+    fn push(&self, producer: &mut Producer, bytes: &[u8]) {
+        let read = self.read.load(Ordering::Acquire);
+        let buffer = self.buffer.get().cast::<u8>();
+        for byte in bytes {
+            if producer.head.wrapping_sub(read) >= BUFFER_SIZE {
+                producer.overflow = true;
+                return;
+            }
+            let index = producer.head % BUFFER_SIZE;
+            // SAFETY: The bytes between the read offset and head belong to the producer. No
+            // reference to the ring is made, as the consumer reads the rest of it meanwhile.
+            unsafe { buffer.add(index).write_volatile(*byte) };
+            producer.head = producer.head.wrapping_add(1);
+        }
+    }
+}
+
+static STATE: [CoreState; 2] = [CoreState::new(), CoreState::new()];
+
+fn current() -> &'static CoreState {
+    &STATE[SIO.cpuid().read() as usize]
+}
+
+#[defmt::global_logger]
+struct Logger;
+
+unsafe impl defmt::Logger for Logger {
+    fn acquire() {
+        let restore_interrupts = cortex_m::register::primask::read().is_active();
+        cortex_m::interrupt::disable();
+
+        let state = current();
+        // SAFETY: Interrupts are disabled and the state is only accessed by this core.
+        let producer = unsafe { &mut *state.producer.get() };
+        if producer.taken {
+            panic!("defmt logger taken reentrantly")
+        }
+        producer.taken = true;
+        producer.restore_interrupts = restore_interrupts;
+        producer.head = state.write.load(Ordering::Relaxed);
+        producer.overflow = false;
+
+        // SAFETY: As above.
+        let encoder = unsafe { &mut *state.encoder.get() };
+        encoder.start_frame(|bytes| state.push(producer, bytes));
+    }
+
+    unsafe fn flush() {}
+
+    unsafe fn release() {
+        let state = current();
+        let producer = &mut *state.producer.get();
+        let encoder = &mut *state.encoder.get();
+        encoder.end_frame(|bytes| state.push(producer, bytes));
+
+        if producer.overflow {
+            let dropped = state.dropped.load(Ordering::Relaxed);
+            state.dropped.store(dropped + 1, Ordering::Relaxed);
+        } else {
+            // Publish the complete frame.
+            state.write.store(producer.head, Ordering::Release);
+        }
+
+        producer.taken = false;
+        if producer.restore_interrupts {
+            cortex_m::interrupt::enable();
+        }
+    }
+
+    unsafe fn write(bytes: &[u8]) {
+        let state = current();
+        let producer = &mut *state.producer.get();
+        let encoder = &mut *state.encoder.get();
+        encoder.write(bytes, |bytes| state.push(producer, bytes));
+    }
+}
+
+/// Stream the logs of both cores to a TCP client connected to `port`. Frames logged whilst no
+/// client is connected are retained until the rings fill up.
+pub async fn listen(stack: &'static embassy_net::Stack<impl Driver>, port: u16) -> ! {
+    let mut rx_buffer = [0; 64];
+    let mut tx_buffer = [0; BUFFER_SIZE];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    let mut chunk = [0; CHUNK_SIZE];
+
+    loop {
+        if socket.accept(port).await.is_err() {
+            continue;
+        }
+
+        'connection: loop {
+            for state in STATE.iter() {
+                let mut read = state.read.load(Ordering::Relaxed);
+                let write = state.write.load(Ordering::Acquire);
+                while read != write {
+                    // The ring is written by the other core meanwhile, so no reference to it is
+                    // held: each chunk is copied out before being sent.
+                    let len = core::cmp::min(write.wrapping_sub(read), CHUNK_SIZE);
+                    let buffer = state.buffer.get().cast::<u8>();
+                    for (i, byte) in chunk[..len].iter_mut().enumerate() {
+                        let index = read.wrapping_add(i) % BUFFER_SIZE;
+                        // SAFETY: The bytes between the read and write offsets are complete
+                        // frames which the producer won't modify until the read offset is
+                        // advanced.
+                        *byte = unsafe { buffer.add(index).read_volatile() };
+                    }
+                    if socket.write_all(&chunk[..len]).await.is_err() {
+                        break 'connection;
+                    }
+                    read = read.wrapping_add(len);
+                    state.read.store(read, Ordering::Release);
+                }
+            }
+            Timer::after(POLL_INTERVAL).await;
+        }
+
+        socket.abort();
+        let _ = socket.flush().await;
+    }
+}
+
+/// The number of frames each core has discarded because its ring was full.
+pub fn dropped_frames() -> [usize; 2] {
+    [
+        STATE[0].dropped.load(Ordering::Relaxed),
+        STATE[1].dropped.load(Ordering::Relaxed),
+    ]
+}
@@ -0,0 +1,154 @@
+//! Reset reasons recorded before every reset this crate performs.
+//!
+//! The reason is recorded in the watchdog scratch registers (0-2, the bootrom uses 4-7) which
+//! survive a watchdog reset, and a panic message in uninitialised RAM. They are read back
+//! after boot with [`crate::OtaDebugger::reset_reason`].
+use core::fmt::Write;
+use core::mem::MaybeUninit;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use cortex_m::asm::nop;
+use defmt::Format;
+use embassy_rp::pac::WATCHDOG;
+use embassy_rp::watchdog::Watchdog;
+use embassy_rp::Peripherals;
+
+use crate::debug::crash::CrashReason;
+
+/// The maximum length of a recorded panic message, longer messages are truncated.
+pub const MAX_PANIC_MESSAGE: usize = 128;
+
+const MAGIC: u32 = 0x7265_7365;
+
+const OTA: u32 = 1;
+const PANIC: u32 = 2;
+const CRASH: u32 = 3;
+const TRIAL_FAILED: u32 = 4;
+const DEBUGGER: u32 = 5;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum ResetReason {
+    /// The flash algorithm was run by a debug session, e.g. a firmware update was downloaded.
+    Ota,
+    /// The application panicked, see [`crate::OtaDebugger::panic_message`].
+    Panic,
+    /// The monitored core crashed and a crash dump was captured.
+    Crash(CrashReason),
+    /// An updated image failed its health checks, see [`crate::OtaDebugger::trial_boot`], so
+    /// the bootloader reverted to the previous image.
+    TrialFailed,
+    /// A remote debugger reset the device, by requesting a system reset through AIRCR.
+    Debugger,
+    /// The watchdog expired without a reason being recorded.
+    WatchdogTimeout,
+}
+
+#[link_section = ".uninit.reset_reason"]
+static mut PANIC_MESSAGE: MaybeUninit<[u8; MAX_PANIC_MESSAGE]> = MaybeUninit::uninit();
+
+/// Set by [`clear`], until the next reset.
+static CLEARED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn record(reason: ResetReason, detail: u32) {
+    let (code, detail) = match reason {
+        ResetReason::Ota => (OTA, detail),
+        ResetReason::Panic => (PANIC, detail),
+        ResetReason::Crash(reason) => (CRASH, reason as u32),
+        ResetReason::TrialFailed => (TRIAL_FAILED, detail),
+        ResetReason::Debugger => (DEBUGGER, detail),
+        // Never recorded, inferred from the watchdog.
+        ResetReason::WatchdogTimeout => return,
+    };
+    WATCHDOG.scratch1().write_value(code);
+    WATCHDOG.scratch2().write_value(detail);
+    WATCHDOG.scratch0().write_value(MAGIC);
+}
+
+/// Record the reason and reset the device.
+pub(crate) fn reboot(reason: ResetReason) -> ! {
+    record(reason, 0);
+    trigger_reset()
+}
+
+fn trigger_reset() -> ! {
+    // Safety: This will reboot the device.
+    let p = unsafe { Peripherals::steal() };
+    let mut watchdog = Watchdog::new(p.WATCHDOG);
+    watchdog.trigger_reset();
+    // Not sure why trigger_reset doesn't return !, so we loop here.
+    loop {
+        nop();
+    }
+}
+
+struct MessageWriter<'a> {
+    buffer: &'a mut [u8; MAX_PANIC_MESSAGE],
+    len: usize,
+}
+
+impl Write for MessageWriter<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        let n = core::cmp::min(s.len(), MAX_PANIC_MESSAGE - self.len);
+        self.buffer[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+/// Call from the panic handler to record the panic (and its message) and reset the device.
+pub fn panic_reboot(info: &PanicInfo) -> ! {
+    // SAFETY: The buffer is only written here, immediately before resetting.
+    let buffer = unsafe { (*core::ptr::addr_of_mut!(PANIC_MESSAGE)).assume_init_mut() };
+    let mut writer = MessageWriter { buffer, len: 0 };
+    let _ = write!(writer, "{}", info);
+    record(ResetReason::Panic, writer.len as u32);
+    trigger_reset()
+}
+
+/// The reason recorded before the last reset, if any.
+pub(crate) fn reason() -> Option<ResetReason> {
+    if CLEARED.load(Ordering::Relaxed) {
+        return None;
+    }
+    if WATCHDOG.scratch0().read() != MAGIC {
+        // Nothing was recorded, the watchdog reason distinguishes a timeout from a forced
+        // reset (or a power on/external reset, which clears it).
+        return WATCHDOG
+            .reason()
+            .read()
+            .timer()
+            .then_some(ResetReason::WatchdogTimeout);
+    }
+    match (WATCHDOG.scratch1().read(), WATCHDOG.scratch2().read()) {
+        (OTA, _) => Some(ResetReason::Ota),
+        (PANIC, _) => Some(ResetReason::Panic),
+        (CRASH, detail) => CrashReason::from_u32(detail).map(ResetReason::Crash),
+        (TRIAL_FAILED, _) => Some(ResetReason::TrialFailed),
+        (DEBUGGER, _) => Some(ResetReason::Debugger),
+        _ => None,
+    }
+}
+
+/// The message recorded by [`panic_reboot`] before the last reset, if it was a panic.
+pub(crate) fn panic_message() -> Option<&'static str> {
+    if reason() != Some(ResetReason::Panic) {
+        return None;
+    }
+    let len = core::cmp::min(WATCHDOG.scratch2().read() as usize, MAX_PANIC_MESSAGE);
+    // SAFETY: The first `len` bytes were written before the reset.
+    let buffer = unsafe { (*core::ptr::addr_of!(PANIC_MESSAGE)).assume_init_ref() };
+    // The message may have been truncated part way through a character.
+    let message = &buffer[..len];
+    match core::str::from_utf8(message) {
+        Ok(message) => Some(message),
+        Err(e) => core::str::from_utf8(&message[..e.valid_up_to()]).ok(),
+    }
+}
+
+/// Forget the reason until the next reset. The scratch registers are invalidated rather than
+/// recording "no reason", as they survive the reset, which may then be a watchdog timeout.
+pub(crate) fn clear() {
+    CLEARED.store(true, Ordering::Relaxed);
+    WATCHDOG.scratch0().write_value(0);
+}
@@ -0,0 +1,108 @@
+//! Stack high-water mark measurement.
+//!
+//! Both stacks are painted with a known pattern by [`crate::OtaDebugger::new`], before core1
+//! is started. The high-water mark is then the deepest point at which the pattern has been
+//! overwritten. Core0's stack is painted from its current depth, which is already included in
+//! the high-water mark as everything above it is assumed used.
+use core::ops::Range;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use defmt::Format;
+
+const PAINT: u32 = 0xdeca_fbad;
+
+/// Space left unpainted below the stack pointer for the painting function itself.
+const MARGIN: u32 = 256;
+
+extern "C" {
+    // The initial stack pointer and end of the statics, defined by cortex-m-rt.
+    static _stack_start: u32;
+    static __sdata: u32;
+    static __sheap: u32;
+    // The start of RAM (excluding the region reserved for the flash algorithm), see memory.x.
+    static __ram_start: u32;
+}
+
+/// The bounds of each core's stack, zero until painted.
+static STACKS: [[AtomicU32; 2]; 2] = [
+    [AtomicU32::new(0), AtomicU32::new(0)],
+    [AtomicU32::new(0), AtomicU32::new(0)],
+];
+
+#[derive(Clone, Copy, Default, Format)]
+pub struct StackUsage {
+    /// The size of the stack in bytes, zero if it hasn't been painted.
+    pub size: u32,
+    /// The maximum number of bytes used.
+    pub used: u32,
+}
+
+/// The bounds of core0's stack, which is below the statics when linked with flip-link.
+fn core0_stack() -> Range<u32> {
+    // SAFETY: Only the addresses of the linker symbols are used.
+    let (top, statics, heap, ram) = unsafe {
+        (
+            &_stack_start as *const u32 as u32,
+            &__sdata as *const u32 as u32,
+            &__sheap as *const u32 as u32,
+            &__ram_start as *const u32 as u32,
+        )
+    };
+    if top <= statics {
+        ram..top
+    } else {
+        heap..top
+    }
+}
+
+fn paint(stack: Range<u32>) {
+    let mut address = (stack.start + 3) & !0b11;
+    while address + 4 <= stack.end {
+        // SAFETY: The range is unused stack.
+        unsafe { write_volatile(address as *mut u32, PAINT) };
+        address += 4;
+    }
+}
+
+fn record(core: usize, stack: &Range<u32>) {
+    STACKS[core][0].store(stack.start, Ordering::Relaxed);
+    STACKS[core][1].store(stack.end, Ordering::Relaxed);
+}
+
+/// Paint core0's stack below the current stack pointer. Interrupts are disabled meanwhile, as
+/// a handler could otherwise be using the stack below the margin being painted.
+#[inline(never)]
+pub(crate) fn paint_core0() {
+    let stack = core0_stack();
+    cortex_m::interrupt::free(|_| {
+        let sp = cortex_m::register::msp::read();
+        paint(stack.start..sp.saturating_sub(MARGIN));
+    });
+    record(0, &stack);
+}
+
+/// Paint core1's (not yet running) stack.
+pub(crate) fn paint_core1(stack: &mut [u8]) {
+    let start = stack.as_mut_ptr() as u32;
+    let stack = start..start + stack.len() as u32;
+    paint(stack.clone());
+    record(1, &stack);
+}
+
+/// The high-water mark of each core's stack.
+pub fn usage() -> [StackUsage; 2] {
+    [0, 1].map(|core| {
+        let start = STACKS[core][0].load(Ordering::Relaxed);
+        let end = STACKS[core][1].load(Ordering::Relaxed);
+        let mut address = (start + 3) & !0b11;
+        // SAFETY: The range is within the recorded stack.
+        while address + 4 <= end && unsafe { read_volatile(address as *const u32) } == PAINT {
+            address += 4;
+        }
+        StackUsage {
+            size: end - start,
+            used: end - core::cmp::min(address, end),
+        }
+    })
+}
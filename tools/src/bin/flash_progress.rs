@@ -0,0 +1,32 @@
+//! Print the progress of the flash algorithm, e.g. `flash_progress 192.168.1.2:1235`.
+use std::{io, net::TcpStream, process::exit};
+
+use embassy_net_rp_self_debug_tools::query;
+
+const COMMAND_FLASH: u8 = 0x03;
+const STATES: [&str; 4] = ["idle", "erasing", "programming", "verifying"];
+
+fn main() -> io::Result<()> {
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    let [address] = args.as_slice() else {
+        eprintln!("usage: flash_progress <host:port>");
+        exit(2);
+    };
+    let mut stream = TcpStream::connect(address)?;
+    let payload = query(&mut stream, COMMAND_FLASH)?;
+    let words: Vec<_> = payload
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .collect();
+    let [state, address, erased, programmed, skipped, errors, error_address] = words[..] else {
+        eprintln!("unexpected response length {}", payload.len());
+        exit(1);
+    };
+    let state = STATES.get(state as usize).unwrap_or(&"unknown");
+    println!("{state} at {address:#010x}");
+    println!("{erased} bytes erased, {programmed} bytes programmed ({skipped} sectors unchanged)");
+    if errors > 0 {
+        println!("{errors} errors, most recently at {error_address:#010x}");
+    }
+    Ok(())
+}
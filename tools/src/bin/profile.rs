@@ -0,0 +1,96 @@
+//! Collect samples from the profiler and print them as folded stacks, e.g.
+//!
+//! ```sh
+//! profile firmware.elf 192.168.1.2:1236 --duration 30 > folded.txt
+//! inferno-flamegraph folded.txt > flamegraph.svg
+//! ```
+use std::{
+    collections::HashMap,
+    io::{self, Read, Write},
+    net::TcpStream,
+    process::exit,
+    time::{Duration, Instant},
+};
+
+use embassy_net_rp_self_debug_tools::Symbols;
+
+const USAGE: &str = "usage: profile <elf> <host:port> [--interval <us>] [--depth <frames>] \
+                     [--duration <seconds>]";
+
+struct Args {
+    elf: String,
+    address: String,
+    interval: u32,
+    depth: u8,
+    duration: Duration,
+}
+
+fn parse_args() -> Option<Args> {
+    let mut args = std::env::args().skip(1);
+    let mut parsed = Args {
+        elf: args.next()?,
+        address: args.next()?,
+        interval: 10_000,
+        depth: 8,
+        duration: Duration::from_secs(10),
+    };
+    while let Some(flag) = args.next() {
+        let value = args.next()?;
+        match flag.as_str() {
+            "--interval" => parsed.interval = value.parse().ok()?,
+            "--depth" => parsed.depth = value.parse().ok()?,
+            "--duration" => parsed.duration = Duration::from_secs(value.parse().ok()?),
+            _ => return None,
+        }
+    }
+    Some(parsed)
+}
+
+fn main() -> io::Result<()> {
+    let Some(args) = parse_args() else {
+        eprintln!("{USAGE}");
+        exit(2);
+    };
+    let symbols = Symbols::load(&args.elf)?;
+
+    let mut stream = TcpStream::connect(&args.address)?;
+    let mut config = [0; 8];
+    config[..4].copy_from_slice(&args.interval.to_le_bytes());
+    config[4] = args.depth;
+    stream.write_all(&config)?;
+
+    let start = Instant::now();
+    let mut stacks: HashMap<String, usize> = HashMap::new();
+    let mut samples = 0;
+    while let Some(remaining) = args.duration.checked_sub(start.elapsed()) {
+        stream.set_read_timeout(Some(remaining.max(Duration::from_millis(1))))?;
+        let mut header = [0; 12];
+        if let Err(e) = stream.read_exact(&mut header) {
+            if matches!(
+                e.kind(),
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ) {
+                break;
+            }
+            return Err(e);
+        }
+        let mut frames = vec![0; 4 * header[8] as usize];
+        stream.read_exact(&mut frames)?;
+
+        // Folded stacks are ordered from the root to the leaf.
+        let stack: Vec<_> = frames
+            .chunks_exact(4)
+            .rev()
+            .map(|frame| symbols.name(u32::from_le_bytes(frame.try_into().unwrap())))
+            .collect();
+        *stacks.entry(stack.join(";")).or_default() += 1;
+        samples += 1;
+    }
+
+    let mut stdout = io::stdout().lock();
+    for (stack, count) in &stacks {
+        writeln!(stdout, "{stack} {count}")?;
+    }
+    eprintln!("{samples} samples, {} unique stacks", stacks.len());
+    Ok(())
+}
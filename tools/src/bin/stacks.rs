@@ -0,0 +1,30 @@
+//! Print the stack high-water marks of both cores, e.g. `stacks 192.168.1.2:1235`.
+use std::{io, net::TcpStream, process::exit};
+
+use embassy_net_rp_self_debug_tools::query;
+
+const COMMAND_STACKS: u8 = 0x02;
+
+fn main() -> io::Result<()> {
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    let [address] = args.as_slice() else {
+        eprintln!("usage: stacks <host:port>");
+        exit(2);
+    };
+    let mut stream = TcpStream::connect(address)?;
+    let payload = query(&mut stream, COMMAND_STACKS)?;
+    let words: Vec<_> = payload
+        .chunks_exact(4)
+        .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+        .collect();
+    for (core, usage) in words.chunks_exact(2).enumerate() {
+        let [size, used] = [usage[0], usage[1]];
+        if size == 0 {
+            println!("core{core}: not painted");
+        } else {
+            let percent = 100.0 * used as f64 / size as f64;
+            println!("core{core}: {used} of {size} bytes used ({percent:.1}%)");
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,168 @@
+//! Print the probe-rs target description for the flash algorithm, e.g.
+//! `target_yaml memory.x > rp2040-self-debug.yaml`.
+//!
+//! The trampoline is generated from the constants in `src/flash/layout.rs`, and decoded again to
+//! check it before anything is printed. Addresses are those of the running image, which the
+//! algorithm maps onto the DFU partition unless routed elsewhere, so the flash range covers every
+//! flash region for any routed partitions. The page buffers are placed in the `SCRATCH_A` and
+//! `SCRATCH_B` regions, which must match the layout, so that probe-rs can double buffer. Only
+//! `data_load_address` (`SCRATCH_A`) is given, probe-rs derives the second buffer from
+//! `data_load_address + page_size`, so `SCRATCH_B` must immediately follow `SCRATCH_A`. The
+//! trampolines are encoded for the `OTA_ALGORITHM` region, wherever it is placed.
+use std::{fmt::Write, fs, io, process::exit};
+
+use embassy_net_rp_self_debug_tools::{
+    layout::{
+        load_address, ENTRY_POINTS, MAX_RESERVED_SIZE, MIN_RESERVED_SIZE, PAGE_SIZE,
+        SCRATCH_A_ADDRESS, SCRATCH_B_ADDRESS, STACK_SIZE,
+    },
+    memory_x::{self, Region},
+    trampoline,
+};
+
+/// The RP2040's flash is erased in 4 KiB sectors.
+const SECTOR_SIZE: u64 = 0x1000;
+const SRAM_BASE: u64 = 0x2000_0000;
+
+fn region<'a>(regions: &'a [Region], name: &str) -> &'a Region {
+    regions
+        .iter()
+        .find(|region| region.name == name)
+        .unwrap_or_else(|| {
+            eprintln!("memory.x has no {name} region");
+            exit(1);
+        })
+}
+
+fn main() -> io::Result<()> {
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    let (path, name) = match args.as_slice() {
+        [path] => (path, "rp2040-self-debug"),
+        [path, name] => (path, name.as_str()),
+        _ => {
+            eprintln!("usage: target_yaml <memory.x> [name]");
+            exit(2);
+        }
+    };
+    let regions = memory_x::parse(&fs::read_to_string(path)?).unwrap_or_else(|e| {
+        eprintln!("{path}: {e}");
+        exit(1);
+    });
+
+    let reserved = region(&regions, "OTA_ALGORITHM");
+    let (base, size) = (reserved.origin as usize, reserved.length as usize);
+    if !(MIN_RESERVED_SIZE..=MAX_RESERVED_SIZE).contains(&size) || base % 4 != 0 || size % 4 != 0 {
+        eprintln!(
+            "OTA_ALGORITHM must be word aligned, and {MIN_RESERVED_SIZE} to {MAX_RESERVED_SIZE} bytes"
+        );
+        exit(1);
+    }
+    if (base + size) % 32 != 0 {
+        eprintln!("OTA_ALGORITHM must end on a 32 byte boundary, for the MPU");
+        exit(1);
+    }
+
+    let instructions = trampoline::encode(base, size);
+    let encoded = trampoline::base64_encode(&instructions);
+    let decoded = trampoline::base64_decode(&encoded);
+    if let Err(e) = decoded
+        .filter(|decoded| *decoded == instructions)
+        .ok_or_else(|| "base64 round trip failed".to_owned())
+        .and_then(|decoded| trampoline::check(&decoded, base, size))
+    {
+        eprintln!("invalid trampoline: {e}");
+        exit(1);
+    }
+
+    let flash_base = region(&regions, "BOOT2").origin;
+    let flash_end = flash_base + region(&regions, "FLASH").length;
+    let flash_end = regions
+        .iter()
+        .filter(|region| region.origin < SRAM_BASE)
+        .map(Region::end)
+        .fold(flash_end, u64::max)
+        .next_multiple_of(SECTOR_SIZE);
+    let ram = regions.iter().filter(|region| region.origin >= SRAM_BASE);
+    let ram_start = ram
+        .clone()
+        .map(|region| region.origin)
+        .min()
+        .unwrap_or(SRAM_BASE);
+    let ram_end = ram.map(Region::end).max().unwrap_or(SRAM_BASE);
+
+    for (name, address) in [
+        ("SCRATCH_A", SCRATCH_A_ADDRESS),
+        ("SCRATCH_B", SCRATCH_B_ADDRESS),
+    ] {
+        let scratch = region(&regions, name);
+        if scratch.origin != address as u64 || scratch.length < PAGE_SIZE as u64 {
+            eprintln!("{name} must be a page ({PAGE_SIZE:#x} bytes) at {address:#x}");
+            exit(1);
+        }
+    }
+
+    let mut entry_points = String::new();
+    for (index, entry_point) in ENTRY_POINTS.iter().enumerate() {
+        let offset = trampoline::entry_point(index);
+        writeln!(entry_points, "  {entry_point}: {offset:#x}").unwrap();
+    }
+
+    print!(
+        "\
+name: {name}
+manufacturer:
+  id: 0x13
+  cc: 0x9
+variants:
+- name: {name}
+  # Only core0 is described, core1 runs the debugger.
+  cores:
+  - name: core0
+    type: armv6m
+    core_access_options: !Arm
+      ap: 0
+      psel: 0x01002927
+  memory_map:
+  - !Nvm
+    range:
+      start: {flash_base:#x}
+      end: {flash_end:#x}
+    cores:
+    - core0
+    is_boot_memory: true
+  - !Ram
+    range:
+      start: {ram_start:#x}
+      end: {ram_end:#x}
+    cores:
+    - core0
+  flash_algorithms:
+  - {name}
+flash_algorithms:
+- name: {name}
+  description: Writes to the DFU partition of embassy-net-rp-self-debug
+  default: true
+  instructions: {encoded}
+  load_address: {load_address:#x}
+  data_load_address: {SCRATCH_A_ADDRESS:#x}
+{entry_points}  data_section_offset: {data_section_offset:#x}
+  stack_size: {STACK_SIZE}
+  flash_properties:
+    address_range:
+      start: {flash_base:#x}
+      end: {flash_end:#x}
+    page_size: {PAGE_SIZE:#x}
+    erased_byte_value: 0xff
+    program_page_timeout: 1000
+    erase_sector_timeout: 2000
+    sectors:
+    - size: {SECTOR_SIZE:#x}
+      address: 0x0
+  cores:
+  - core0
+",
+        load_address = load_address(base),
+        data_section_offset = instructions.len(),
+    );
+    Ok(())
+}
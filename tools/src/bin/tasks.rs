@@ -0,0 +1,46 @@
+//! List the tasks of the application's executors, e.g. `tasks firmware.elf 192.168.1.2:1235`.
+//!
+//! Tasks are named after the static pool holding them if there is one (i.e. on nightly),
+//! otherwise after their poll function. Building the firmware with
+//! `-C symbol-mangling-version=v0` includes the future's type in the poll function's name.
+use std::{io, net::TcpStream, process::exit};
+
+use embassy_net_rp_self_debug_tools::{query, Symbols};
+
+const COMMAND_TASKS: u8 = 0x01;
+const TASK_INFO_SIZE: usize = 24;
+
+fn main() -> io::Result<()> {
+    let args: Vec<_> = std::env::args().skip(1).collect();
+    let [elf, address] = args.as_slice() else {
+        eprintln!("usage: tasks <elf> <host:port>");
+        exit(2);
+    };
+    let symbols = Symbols::load(elf)?;
+    let mut stream = TcpStream::connect(address)?;
+    let payload = query(&mut stream, COMMAND_TASKS)?;
+    let now = u64::from_le_bytes(payload[..8].try_into().unwrap());
+
+    println!("{:<10} {:<4} {:<24} task", "address", "core", "state");
+    for task in payload[8..].chunks_exact(TASK_INFO_SIZE) {
+        let word = |offset: usize| u32::from_le_bytes(task[offset..offset + 4].try_into().unwrap());
+        let (header, poll_fn, core, state) = (word(0), word(4), task[8], task[9]);
+        let expires_at = u64::from_le_bytes(task[16..24].try_into().unwrap());
+
+        let state = if state & 0b010 != 0 {
+            "queued".to_owned()
+        } else if state & 0b100 != 0 {
+            // Ticks are microseconds on the RP2040.
+            let remaining = expires_at.saturating_sub(now) as f64 / 1e6;
+            format!("timer ({remaining:.3}s)")
+        } else {
+            "waiting".to_owned()
+        };
+        let name = symbols
+            .lookup_data(header)
+            .map(str::to_owned)
+            .unwrap_or_else(|| symbols.name(poll_fn));
+        println!("{header:#010x} {core:<4} {state:<24} {name}");
+    }
+    Ok(())
+}
@@ -0,0 +1,99 @@
+//! Host-side tools for the services exposed by `embassy-net-rp-self-debug`.
+use std::{
+    fs,
+    io::{self, Read, Write},
+    net::TcpStream,
+    path::Path,
+};
+
+use object::{Object, ObjectSymbol, SymbolKind};
+
+#[path = "../../src/flash/layout.rs"]
+pub mod layout;
+pub mod memory_x;
+pub mod trampoline;
+
+/// The function and data symbols of a firmware ELF, for resolving addresses.
+pub struct Symbols {
+    /// Sorted by address.
+    functions: Vec<Symbol>,
+    /// Sorted by address.
+    data: Vec<Symbol>,
+}
+
+struct Symbol {
+    address: u64,
+    size: u64,
+    name: String,
+}
+
+impl Symbols {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        let file = object::File::parse(&*data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let symbols = |kind: SymbolKind| {
+            let mut symbols: Vec<_> = file
+                .symbols()
+                .filter(|symbol| symbol.kind() == kind)
+                .filter_map(|symbol| {
+                    let name = symbol.name().ok()?;
+                    Some(Symbol {
+                        // Clear the Thumb bit.
+                        address: symbol.address() & !1,
+                        size: symbol.size(),
+                        name: format!("{:#}", rustc_demangle::demangle(name)),
+                    })
+                })
+                .collect();
+            symbols.sort_by_key(|symbol| symbol.address);
+            symbols
+        };
+        Ok(Self {
+            functions: symbols(SymbolKind::Text),
+            data: symbols(SymbolKind::Data),
+        })
+    }
+
+    fn find(symbols: &[Symbol], address: u32) -> Option<&str> {
+        let address = address as u64;
+        let index = symbols
+            .partition_point(|symbol| symbol.address <= address)
+            .checked_sub(1)?;
+        let symbol = &symbols[index];
+        (symbol.size == 0 || address < symbol.address + symbol.size).then_some(&symbol.name)
+    }
+
+    /// The name of the function containing `address`.
+    pub fn lookup(&self, address: u32) -> Option<&str> {
+        Self::find(&self.functions, address)
+    }
+
+    /// The name of the static containing `address`.
+    pub fn lookup_data(&self, address: u32) -> Option<&str> {
+        Self::find(&self.data, address)
+    }
+
+    /// The name of the function containing `address`, or the address if unknown.
+    pub fn name(&self, address: u32) -> String {
+        self.lookup(address)
+            .map(str::to_owned)
+            .unwrap_or_else(|| format!("{address:#010x}"))
+    }
+}
+
+/// Send a command to the diagnostics service and return the response payload.
+pub fn query(stream: &mut TcpStream, command: u8) -> io::Result<Vec<u8>> {
+    stream.write_all(&[command])?;
+    let mut header = [0; 8];
+    stream.read_exact(&mut header)?;
+    if header[0] != command || header[1] != 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("command {command:#04x} failed with status {}", header[1]),
+        ));
+    }
+    let mut payload = vec![0; u32::from_le_bytes(header[4..].try_into().unwrap()) as usize];
+    stream.read_exact(&mut payload)?;
+    Ok(payload)
+}
@@ -0,0 +1,93 @@
+//! A parser for the `MEMORY` block of a linker script such as `memory.x`.
+
+pub struct Region {
+    pub name: String,
+    pub origin: u64,
+    pub length: u64,
+}
+
+impl Region {
+    pub fn end(&self) -> u64 {
+        self.origin + self.length
+    }
+}
+
+/// Evaluate a sum of numbers, e.g. `256K - 1k` or `0x10000000`.
+fn evaluate(expression: &str) -> Result<u64, String> {
+    let mut total: i64 = 0;
+    let mut sign = 1;
+    for token in expression.split_inclusive(['+', '-']) {
+        let (term, next_sign) = match token.strip_suffix('+') {
+            Some(term) => (term, 1),
+            None => match token.strip_suffix('-') {
+                Some(term) => (term, -1),
+                None => (token, 1),
+            },
+        };
+        let term = term.trim();
+        let (digits, multiplier) = match term.chars().last() {
+            Some('K' | 'k') => (&term[..term.len() - 1], 1024),
+            Some('M' | 'm') => (&term[..term.len() - 1], 1024 * 1024),
+            _ => (term, 1),
+        };
+        let value = match digits
+            .strip_prefix("0x")
+            .or_else(|| digits.strip_prefix("0X"))
+        {
+            Some(hex) => i64::from_str_radix(hex, 16),
+            None => digits.parse(),
+        }
+        .map_err(|_| format!("invalid number {term:?} in {expression:?}"))?;
+        total += sign * value * multiplier;
+        sign = next_sign;
+    }
+    u64::try_from(total).map_err(|_| format!("negative value {expression:?}"))
+}
+
+fn strip_comments(text: &str) -> String {
+    let mut stripped = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("/*") {
+        stripped.push_str(&rest[..start]);
+        rest = rest[start..].split_once("*/").map_or("", |(_, rest)| rest);
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+/// Parse the regions of the `MEMORY` block.
+pub fn parse(text: &str) -> Result<Vec<Region>, String> {
+    let text = strip_comments(text);
+    let block = text
+        .split_once("MEMORY")
+        .and_then(|(_, rest)| rest.split_once('{'))
+        .and_then(|(_, rest)| rest.split_once('}'))
+        .map(|(block, _)| block)
+        .ok_or("no MEMORY block")?;
+
+    block
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let (name, attributes) = line
+                .split_once(':')
+                .ok_or_else(|| format!("invalid region {line:?}"))?;
+            // Drop any access attributes, e.g. `RAM (rwx)`.
+            let name = name.split('(').next().unwrap_or(name).trim().to_owned();
+            let field = |field: &str| {
+                attributes
+                    .split(',')
+                    .filter_map(|a| a.split_once('='))
+                    .find(|(key, _)| key.trim().eq_ignore_ascii_case(field))
+                    .ok_or_else(|| format!("{name} has no {field}"))
+                    .and_then(|(_, value)| evaluate(value))
+            };
+            Ok(Region {
+                origin: field("ORIGIN")?,
+                length: field("LENGTH")?,
+                name,
+            })
+        })
+        .collect()
+}
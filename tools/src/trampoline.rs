@@ -0,0 +1,203 @@
+//! Encoding of the flash algorithm trampoline described in `src/flash/algorithm.rs`.
+//!
+//! Each entry point is an `ldr r4, [pc, #imm]` loading its function table entry followed by a
+//! `b` to the shared `push {lr}; blx r4; pop {pc}` call sequence.
+//!
+//! The reserved region (`OTA_ALGORITHM` in memory.x) is given as its base address and size.
+use crate::layout::{load_address, table_base_address, ENTRY_POINT_COUNT};
+
+const PUSH_LR: u16 = 0xb500;
+const BLX_R4: u16 = 0x47a0;
+const POP_PC: u16 = 0xbd00;
+
+/// "the value of the PC is the address of the current instruction plus 4 bytes"
+const PC_OFFSET: usize = 4;
+const ENTRY_POINT_SIZE: usize = 4;
+
+fn call_sequence(base: usize) -> usize {
+    load_address(base) + ENTRY_POINT_COUNT * ENTRY_POINT_SIZE
+}
+
+/// The thumb-mode offset of entry point `index` from the load address.
+pub fn entry_point(index: usize) -> usize {
+    (index * ENTRY_POINT_SIZE) | 1
+}
+
+/// The `ldr` PC value, which is word aligned.
+fn ldr_pc(address: usize) -> usize {
+    (address + PC_OFFSET) & !0b11
+}
+
+pub fn encode(base: usize, size: usize) -> Vec<u8> {
+    let mut instructions = Vec::new();
+    for index in 0..ENTRY_POINT_COUNT {
+        let ldr = load_address(base) + index * ENTRY_POINT_SIZE;
+        let offset = table_base_address(base, size) + 4 * index - ldr_pc(ldr);
+        assert!(
+            offset.is_multiple_of(4) && offset / 4 <= 0xff,
+            "table out of range"
+        );
+        instructions.push(0x4c00 | (offset / 4) as u16);
+
+        let b = ldr + 2;
+        let offset = call_sequence(base) as isize - (b + PC_OFFSET) as isize;
+        instructions.push(0xe000 | ((offset / 2) as u16 & 0x7ff));
+    }
+    instructions.extend([PUSH_LR, BLX_R4, POP_PC]);
+    instructions.iter().flat_map(|i| i.to_le_bytes()).collect()
+}
+
+/// Decode the instructions, checking each entry point loads its own table entry and branches
+/// to the call sequence.
+pub fn check(bytes: &[u8], base: usize, size: usize) -> Result<(), String> {
+    let instructions: Vec<_> = bytes
+        .chunks_exact(2)
+        .map(|i| u16::from_le_bytes([i[0], i[1]]))
+        .collect();
+    if !bytes.len().is_multiple_of(2) || instructions.len() != 2 * ENTRY_POINT_COUNT + 3 {
+        return Err(format!("unexpected length {}", bytes.len()));
+    }
+    for (index, pair) in instructions
+        .chunks_exact(2)
+        .take(ENTRY_POINT_COUNT)
+        .enumerate()
+    {
+        let address = load_address(base) + index * ENTRY_POINT_SIZE;
+        let (ldr, b) = (pair[0], pair[1]);
+        if ldr & 0xff00 != 0x4c00 {
+            return Err(format!(
+                "entry point {index}: {ldr:#06x} is not ldr r4, [pc, #imm]"
+            ));
+        }
+        let target = ldr_pc(address) + 4 * (ldr & 0xff) as usize;
+        if target != table_base_address(base, size) + 4 * index {
+            return Err(format!("entry point {index}: loads {target:#x}"));
+        }
+        if b & 0xf800 != 0xe000 {
+            return Err(format!("entry point {index}: {b:#06x} is not b"));
+        }
+        // Sign extend the 11 bit offset.
+        let offset = (((b & 0x7ff) << 5) as i16 >> 5) as isize * 2;
+        let target = (address + 2 + PC_OFFSET) as isize + offset;
+        if target != call_sequence(base) as isize {
+            return Err(format!("entry point {index}: branches to {target:#x}"));
+        }
+    }
+    if instructions[2 * ENTRY_POINT_COUNT..] != [PUSH_LR, BLX_R4, POP_PC] {
+        return Err("unexpected call sequence".to_owned());
+    }
+    Ok(())
+}
+
+const BASE64: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+pub fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let value = chunk.iter().enumerate().fold(0u32, |value, (i, byte)| {
+            value | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(value >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+pub fn base64_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    for chunk in encoded.as_bytes().chunks(4) {
+        let digits = chunk.iter().take_while(|c| **c != b'=').count();
+        let mut value = 0;
+        for (i, c) in chunk[..digits].iter().enumerate() {
+            let digit = BASE64.iter().position(|b| b == c)? as u32;
+            value |= digit << (18 - 6 * i);
+        }
+        bytes.extend((0..digits.saturating_sub(1)).map(|i| (value >> (16 - 8 * i)) as u8));
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::{
+        MAX_RESERVED_SIZE, MIN_RESERVED_SIZE, RESERVED_BASE_ADDRESS, RESERVED_SIZE,
+    };
+
+    fn decode(base: usize, size: usize) -> Vec<u16> {
+        let encoded = base64_encode(&encode(base, size));
+        let bytes = base64_decode(&encoded).unwrap();
+        assert_eq!(check(&bytes, base, size), Ok(()));
+        bytes
+            .chunks_exact(2)
+            .map(|i| u16::from_le_bytes([i[0], i[1]]))
+            .collect()
+    }
+
+    /// Each entry point's `b` reaches the call sequence after the last entry point, i.e.
+    /// `9 - 2 * index` halfwords beyond the PC.
+    fn branches() -> impl Iterator<Item = u16> {
+        (0..ENTRY_POINT_COUNT as i16).map(|index| 0xe000 | ((9 - 2 * index) as u16 & 0x7ff))
+    }
+
+    #[test]
+    fn default_region() {
+        assert_eq!((RESERVED_BASE_ADDRESS, RESERVED_SIZE), (0x2000_0000, 1024));
+        let instructions = decode(RESERVED_BASE_ADDRESS, RESERVED_SIZE);
+        // The table at 0x200003e8 is 0x3e0 beyond each (word aligned) PC.
+        for (pair, b) in instructions.chunks_exact(2).zip(branches()) {
+            assert_eq!(pair, [0x4cf8, b]);
+        }
+        assert_eq!(instructions[..2], [0x4cf8, 0xe009]);
+        assert_eq!(instructions[10..12], [0x4cf8, 0xe7ff]);
+        assert_eq!(instructions[12..], [PUSH_LR, BLX_R4, POP_PC]);
+    }
+
+    #[test]
+    fn other_regions() {
+        // The load offset (and so the instructions) only depends on the size.
+        for (base, size, ldr) in [
+            (0x2000_1000, MIN_RESERVED_SIZE, 0x4c89),
+            (0x2004_1000, MAX_RESERVED_SIZE, 0x4cff),
+            (0x2003_fc00, 768, 0x4cb8),
+        ] {
+            let instructions = decode(base, size);
+            assert_eq!(
+                table_base_address(base, size) - ldr_pc(load_address(base)),
+                4 * (ldr & 0xff) as usize
+            );
+            for (pair, b) in instructions.chunks_exact(2).zip(branches()) {
+                assert_eq!(pair, [ldr, b], "{base:#x} + {size}");
+            }
+            assert_eq!(instructions[12..], [PUSH_LR, BLX_R4, POP_PC]);
+        }
+    }
+
+    #[test]
+    fn check_rejects_mismatched_regions() {
+        let bytes = encode(RESERVED_BASE_ADDRESS, RESERVED_SIZE);
+        assert!(check(&bytes, RESERVED_BASE_ADDRESS, RESERVED_SIZE - 4).is_err());
+        assert!(check(
+            &bytes[..bytes.len() - 2],
+            RESERVED_BASE_ADDRESS,
+            RESERVED_SIZE
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(base64_encode(b"Man"), "TWFu");
+        assert_eq!(base64_encode(b"Ma"), "TWE=");
+        assert_eq!(base64_encode(b"M"), "TQ==");
+        for encoded in ["TWFu", "TWE=", "TQ=="] {
+            assert_eq!(base64_encode(&base64_decode(encoded).unwrap()), encoded);
+        }
+        assert_eq!(base64_decode("T!=="), None);
+    }
+}